@@ -5,16 +5,50 @@
 #include <linux/if_ether.h>
 #include <linux/ip.h>
 #include <linux/ipv6.h>
+#include <linux/in.h>
+#include <linux/udp.h>
+
+// Fragment offset bits of iphdr->frag_off
+#define IP_OFFSET 0x1FFF
+
+// errno.h is not available to BPF programs
+#ifndef EEXIST
+#define EEXIST 17
+#endif
+
+typedef enum output {
+    RINGBUF = 0,
+    PERF,
+    AGGREGATE
+} t_output;
 
 void* ptr_offset(struct xdp_md*, size_t, size_t);
-long ipv4(struct xdp_md*);
-long ipv6(struct xdp_md*);
+static __always_inline long pacer(struct xdp_md*, t_output);
+static __always_inline long ipv4(struct xdp_md*, t_output);
+static __always_inline long ipv6(struct xdp_md*, t_output);
 
 struct {
     __uint(type, BPF_MAP_TYPE_RINGBUF);
     __uint(max_entries, 1UL << 20); // 1MiB of data stored
 } packets SEC(".maps");
 
+// Fallback for kernels without BPF ring buffers. Both maps are declared, but userspace disables
+// creation of the maps of unused modes with bpf_map__set_autocreate() before loading.
+struct {
+    __uint(type, BPF_MAP_TYPE_PERF_EVENT_ARRAY);
+    __uint(key_size, sizeof(__u32));
+    __uint(value_size, sizeof(__u32));
+} packets_perf SEC(".maps");
+
+// Fields that identify a source in the aggregate mode, set by userspace before loading
+typedef enum key {
+    KEY_SOURCE = 0,
+    KEY_SOURCE_PROTO,
+    KEY_FIVE_TUPLE
+} t_key;
+
+const volatile __u32 aggregate_key = KEY_FIVE_TUPLE;
+
 typedef enum addr_type {
     IPV4 = 0,
     IPV6
@@ -27,10 +61,109 @@ typedef struct addr {
         __u8 v4[4];
         __u8 v6[16];
     };
+    union {
+        __u8 v4[4];
+        __u8 v6[16];
+    } dst;
+    // Ports in host byte order, zero unless proto is TCP or UDP
+    __u16 sport;
+    __u16 dport;
+    __u8 proto;
+    __u8 pad[3];
 } t_addr;
 
+// A packet as sent to userspace. Only the address is used as a key for aggregation.
+typedef struct packet {
+    struct addr addr;
+    // Length of the frame, from data to data_end
+    __u32 len;
+} t_packet;
+
+static __always_inline void ports(struct xdp_md*, size_t, struct addr*);
+
+typedef struct counts {
+    __u64 packets;
+    __u64 bytes;
+} t_counts;
+
+// Counts per source instead of one record per packet. Each CPU counts into its own slot, and the
+// least recently seen sources make room for new ones.
+struct {
+    __uint(type, BPF_MAP_TYPE_LRU_PERCPU_HASH);
+    __uint(max_entries, 65536);
+    __type(key, struct addr);
+    __type(value, struct counts);
+} sources SEC(".maps");
+
 SEC("xdp")
 int xdp_pacer(struct xdp_md* ctx) {
+    return pacer(ctx, RINGBUF);
+}
+
+SEC("xdp")
+int xdp_pacer_perf(struct xdp_md* ctx) {
+    return pacer(ctx, PERF);
+}
+
+SEC("xdp")
+int xdp_pacer_aggregate(struct xdp_md* ctx) {
+    return pacer(ctx, AGGREGATE);
+}
+
+static __always_inline void count(struct packet* packet) {
+    struct addr source = packet->addr;
+
+    // Fields that are not part of the key are zero, so that their packets are counted together
+    if (aggregate_key != KEY_FIVE_TUPLE) {
+        __builtin_memset(&source.dst, 0, sizeof(source.dst));
+        source.sport = 0;
+        source.dport = 0;
+    }
+
+    if (aggregate_key == KEY_SOURCE) {
+        source.proto = 0;
+    }
+
+    struct counts* counts = bpf_map_lookup_elem(&sources, &source);
+
+    if (counts == NULL) {
+        struct counts initial = {
+            .packets = 1,
+            .bytes = packet->len,
+        };
+
+        // Another CPU might have inserted the source in the meantime. Overwriting it would reset
+        // its counts, so we count into the existing value instead.
+        if (bpf_map_update_elem(&sources, &source, &initial, BPF_NOEXIST) != -EEXIST) {
+            return;
+        }
+
+        if ((counts = bpf_map_lookup_elem(&sources, &source)) == NULL) {
+            return;
+        }
+    }
+
+    // The value belongs to this CPU, so no atomics are needed
+    counts->packets++;
+    counts->bytes += packet->len;
+}
+
+/* Output is a constant in each program, so the compiler removes the unused branch and its map */
+static __always_inline void output(struct xdp_md* ctx, t_output out, struct packet* packet) {
+    switch (out) {
+        case RINGBUF:
+            bpf_ringbuf_output(&packets, packet, sizeof(struct packet), 0);
+            break;
+        case PERF:
+            bpf_perf_event_output(ctx, &packets_perf, BPF_F_CURRENT_CPU, packet, sizeof(struct packet));
+            break;
+        case AGGREGATE:
+            count(packet);
+            break;
+    }
+}
+
+static __always_inline long pacer(struct xdp_md* ctx, t_output out) {
     struct ethhdr* ethhdr;
 
     // Get ethernet header
@@ -46,58 +179,80 @@ int xdp_pacer(struct xdp_md* ctx) {
 
     switch (proto) {
         case ETH_P_IP:
-            return ipv4(ctx);
+            return ipv4(ctx, out);
         case ETH_P_IPV6:
-            return ipv6(ctx);
+            return ipv6(ctx, out);
         default:
             return XDP_PASS;
     }
 }
 
-inline long ipv4(struct xdp_md* ctx) {
+static __always_inline long ipv4(struct xdp_md* ctx, t_output out) {
     struct iphdr* iphdr;
-    struct addr* ring_addr;
+    struct packet ring_packet = {0};
     t_addr_type type = 0;
 
     if ((iphdr = ptr_offset(ctx, sizeof(struct ethhdr), sizeof(struct iphdr))) == NULL) {
         return XDP_PASS;
     }
 
-    if ((ring_addr = bpf_ringbuf_reserve(&packets, sizeof(struct addr), 0)) == NULL) {
-        return XDP_PASS;
-    }
+    __builtin_memcpy(&ring_packet.addr.ifindex, &ctx->ingress_ifindex, 4);
+    __builtin_memcpy(&ring_packet.addr.type, &type, sizeof(t_addr_type));
+    __builtin_memcpy(&ring_packet.addr.v4, &iphdr->saddr, 4);
+    __builtin_memcpy(&ring_packet.addr.dst.v4, &iphdr->daddr, 4);
+    ring_packet.addr.proto = iphdr->protocol;
+    ring_packet.len = ctx->data_end - ctx->data;
 
-    __builtin_memcpy(&ring_addr->ifindex, &ctx->ingress_ifindex, 4);
-    __builtin_memcpy(&ring_addr->type, &type, sizeof(t_addr_type));
-    __builtin_memcpy(&ring_addr->v4, &iphdr->saddr, 4);
+    // Only the first fragment carries the ports
+    if (!(iphdr->frag_off & bpf_htons(IP_OFFSET))) {
+        ports(ctx, sizeof(struct ethhdr) + iphdr->ihl * 4, &ring_packet.addr);
+    }
 
-    bpf_ringbuf_submit(ring_addr, 0);
+    output(ctx, out, &ring_packet);
 
     return XDP_PASS;
 }
 
-inline long ipv6(struct xdp_md* ctx) {
+static __always_inline long ipv6(struct xdp_md* ctx, t_output out) {
     struct ipv6hdr* ip6hdr;
-    struct addr* ring_addr;
+    struct packet ring_packet = {0};
     t_addr_type type = 1;
 
     if ((ip6hdr = ptr_offset(ctx, sizeof(struct ethhdr), sizeof(struct ipv6hdr))) == NULL) {
         return XDP_PASS;
     }
 
-    if ((ring_addr = bpf_ringbuf_reserve(&packets, sizeof(struct addr), 0)) == NULL) {
-        return XDP_PASS;
-    }
+    __builtin_memcpy(&ring_packet.addr.ifindex, &ctx->ingress_ifindex, 4);
+    __builtin_memcpy(&ring_packet.addr.type, &type, sizeof(t_addr_type));
+    __builtin_memcpy(&ring_packet.addr.v4, &ip6hdr->saddr, 16);
+    __builtin_memcpy(&ring_packet.addr.dst.v6, &ip6hdr->daddr, 16);
+    ring_packet.addr.proto = ip6hdr->nexthdr;
+    ring_packet.len = ctx->data_end - ctx->data;
 
-    __builtin_memcpy(&ring_addr->ifindex, &ctx->ingress_ifindex, 4);
-    __builtin_memcpy(&ring_addr->type, &type, sizeof(t_addr_type));
-    __builtin_memcpy(&ring_addr->v4, &ip6hdr->saddr, 16);
+    // Extension headers are not followed, so their packets have no ports
+    ports(ctx, sizeof(struct ethhdr) + sizeof(struct ipv6hdr), &ring_packet.addr);
 
-    bpf_ringbuf_submit(ring_addr, 0);
+    output(ctx, out, &ring_packet);
 
     return XDP_PASS;
 }
 
+/* TCP and UDP headers both start with source and destination port */
+static __always_inline void ports(struct xdp_md* ctx, size_t offset, struct addr* addr) {
+    struct udphdr* udphdr;
+
+    if (!(addr->proto == IPPROTO_TCP || addr->proto == IPPROTO_UDP)) {
+        return;
+    }
+
+    if ((udphdr = ptr_offset(ctx, offset, sizeof(struct udphdr))) == NULL) {
+        return;
+    }
+
+    addr->sport = bpf_ntohs(udphdr->source);
+    addr->dport = bpf_ntohs(udphdr->dest);
+}
+
 /* Validate and return pointer from offset */
 inline void* ptr_offset(struct xdp_md* ctx, size_t offset, size_t len)
 {
@@ -1,122 +1,261 @@
-use libc::{c_int, c_long, c_uint};
+use crate::OsError;
+use libc::{c_int, c_void, MAP_FAILED};
+use std::ops::RangeBounds;
 
 /// Represents an expected non-negative value
 pub(crate) struct ExpectNonNegative;
 
+/// Represents a value that is not all ones, i.e. not -1 for signed and not MAX for unsigned types
 pub(crate) struct ExpectNotMax;
 
+/// Represents an expected non-zero value
 pub(crate) struct ExpectNotZero;
 
+/// Represents an expected value greater than zero
 pub(crate) struct ExpectPositive;
 
 /// Represents an expected non-null pointer
 pub(crate) struct ExpectNonNullPtr;
 
+/// Represents a pointer returned by `mmap`, which signals errors with `MAP_FAILED` instead of null
+pub(crate) struct ExpectNotMapFailed;
+
 /// Represents a value equal to a type's default
 pub(crate) struct ExpectDefault;
 
+/// Represents an `Ok` result
 pub(crate) struct ExpectOk;
 
-impl<T, E> AssertReturn<Result<T, E>> for ExpectOk {
-    fn assert(_: &Result<T, E>) -> bool {
-        todo!()
+/// Represents a value within the given range
+pub(crate) struct ExpectInRange<R>(pub R);
+
+/// Represents a value that passes `S`, or a failure with one of the given errnos, e.g. `EEXIST`
+/// for objects that were already created
+pub(crate) struct ExpectErrnoIn<'e, S>(pub S, pub &'e [c_int]);
+
+pub(crate) trait AssertReturn<T> {
+    fn assert(&self, ret: &T) -> bool;
+
+    /// Like `assert`, with the errno that was captured right after the call returned
+    fn assert_errno(&self, ret: &T, _errno: c_int) -> bool {
+        self.assert(ret)
     }
 }
 
-pub(crate) trait AssertReturn<T> {
-    fn assert(_: &T) -> bool;
+/// Integer types returned by system calls
+pub(crate) trait Integer: Copy + PartialOrd {
+    const ZERO: Self;
+    const ALL_ONES: Self;
 }
 
+macro_rules! integer {
+    ($($t:ty),*; $($u:ty),*) => {
+        $(impl Integer for $t {
+            const ZERO: Self = 0;
+            const ALL_ONES: Self = -1;
+        })*
+        $(impl Integer for $u {
+            const ZERO: Self = 0;
+            const ALL_ONES: Self = <$u>::MAX;
+        })*
+    };
+}
+
+integer!(i8, i16, i32, i64, isize; u8, u16, u32, u64, usize);
+
 impl<T> AssertReturn<T> for ExpectDefault
 where
     T: Default + PartialEq,
 {
-    fn assert(ty: &T) -> bool {
-        ty == &T::default()
+    fn assert(&self, ret: &T) -> bool {
+        ret == &T::default()
+    }
+}
+
+impl<T, E> AssertReturn<Result<T, E>> for ExpectOk {
+    fn assert(&self, ret: &Result<T, E>) -> bool {
+        ret.is_ok()
     }
 }
 
 impl<T> AssertReturn<*mut T> for ExpectNonNullPtr {
-    fn assert(ty: &*mut T) -> bool {
-        !(*ty).is_null()
+    fn assert(&self, ret: &*mut T) -> bool {
+        !ret.is_null()
     }
 }
 
 impl<T> AssertReturn<*const T> for ExpectNonNullPtr {
-    fn assert(ty: &*const T) -> bool {
-        !(*ty).is_null()
+    fn assert(&self, ret: &*const T) -> bool {
+        !ret.is_null()
+    }
+}
+
+impl AssertReturn<*mut c_void> for ExpectNotMapFailed {
+    fn assert(&self, ret: &*mut c_void) -> bool {
+        *ret != MAP_FAILED
+    }
+}
+
+impl<T: Integer> AssertReturn<T> for ExpectNonNegative {
+    fn assert(&self, ret: &T) -> bool {
+        *ret >= T::ZERO
     }
 }
 
-impl AssertReturn<c_int> for ExpectNonNegative {
-    fn assert(ty: &c_int) -> bool {
-        ty >= &0
+impl<T: Integer> AssertReturn<T> for ExpectNotMax {
+    fn assert(&self, ret: &T) -> bool {
+        *ret != T::ALL_ONES
     }
 }
 
-impl AssertReturn<c_int> for ExpectNotMax {
-    fn assert(ty: &c_int) -> bool {
-        *ty as u32 != u32::MAX
+impl<T: Integer> AssertReturn<T> for ExpectNotZero {
+    fn assert(&self, ret: &T) -> bool {
+        *ret != T::ZERO
     }
 }
 
-impl AssertReturn<c_uint> for ExpectNotZero {
-    fn assert(ty: &c_uint) -> bool {
-        ty != &0
+impl<T: Integer> AssertReturn<T> for ExpectPositive {
+    fn assert(&self, ret: &T) -> bool {
+        *ret > T::ZERO
     }
 }
 
-impl AssertReturn<c_int> for ExpectNotZero {
-    fn assert(ty: &c_int) -> bool {
-        ty != &0
+impl<T, R> AssertReturn<T> for ExpectInRange<R>
+where
+    T: Integer,
+    R: RangeBounds<T>,
+{
+    fn assert(&self, ret: &T) -> bool {
+        self.0.contains(ret)
     }
 }
 
-impl AssertReturn<c_long> for ExpectPositive {
-    fn assert(ty: &c_long) -> bool {
-        ty > &0
+impl<'e, T, S> AssertReturn<T> for ExpectErrnoIn<'e, S>
+where
+    S: AssertReturn<T>,
+{
+    fn assert(&self, ret: &T) -> bool {
+        self.0.assert(ret)
+    }
+
+    fn assert_errno(&self, ret: &T, errno: c_int) -> bool {
+        self.0.assert(ret) || self.1.contains(&errno)
     }
 }
 
 pub(crate) struct UnsafeNoPanic<T> {
     res: T,
+    os: OsError,
 }
 
 impl<T> UnsafeNoPanic<T> {
-    pub fn new<F>(f: F) -> Self
+    /// Runs `f` and records `errno` right after it returned, together with the call site
+    pub fn new<F>(f: F, call: &'static str, file: &'static str, line: u32) -> Self
     where
-        F: Fn() -> T,
+        F: FnOnce() -> T,
     {
-        UnsafeNoPanic { res: f() }
-    }
+        let res = f();
+        let errno = std::io::Error::last_os_error().raw_os_error().unwrap_or(0);
 
-    pub fn expect<S, E>(self, _: S, ex: E) -> crate::Result<T>
-    where
-        S: AssertReturn<T>,
-        E: std::error::Error + 'static,
-    {
-        self.check::<S, _>(ex)?;
-
-        Ok(self.res)
+        UnsafeNoPanic {
+            res,
+            os: OsError {
+                errno,
+                call,
+                file,
+                line,
+            },
+        }
     }
 
-    fn check<S, E>(&self, ex: E) -> crate::Result<()>
+    /// Returns the result if it passes the assertion `S`. Otherwise the recorded call is handed
+    /// to `err`, which builds the error describing the failed operation.
+    pub fn expect<S, F>(self, assertion: S, err: F) -> crate::Result<T>
     where
         S: AssertReturn<T>,
-        E: std::error::Error + 'static,
+        F: FnOnce(OsError) -> crate::Error,
     {
-        if !S::assert(&self.res) {
-            return Err(ex.into());
+        if !assertion.assert_errno(&self.res, self.os.errno) {
+            return Err(err(self.os));
         }
 
-        Ok(())
+        Ok(self.res)
     }
 }
 
 macro_rules! unsafe_no_panic {
     ($j:expr) => {
-        crate::assert::UnsafeNoPanic::new(|| unsafe { $j })
+        crate::assert::UnsafeNoPanic::new(|| unsafe { $j }, stringify!($j), file!(), line!())
     };
 }
 
 pub(crate) use unsafe_no_panic;
+
+#[cfg(test)]
+mod test {
+    use crate::assert::{
+        AssertReturn, ExpectDefault, ExpectErrnoIn, ExpectInRange, ExpectNonNegative,
+        ExpectNonNullPtr, ExpectNotMapFailed, ExpectNotMax, ExpectNotZero, ExpectOk,
+        ExpectPositive,
+    };
+    use libc::{c_void, EEXIST, ENOENT, MAP_FAILED};
+    use std::ptr::null_mut;
+
+    #[test]
+    fn integers() {
+        assert!(ExpectNonNegative.assert(&0i8));
+        assert!(!ExpectNonNegative.assert(&-1i64));
+        assert!(ExpectNonNegative.assert(&0usize));
+
+        assert!(ExpectNotMax.assert(&0i32));
+        assert!(!ExpectNotMax.assert(&-1i32));
+        assert!(ExpectNotMax.assert(&i32::MAX));
+        assert!(!ExpectNotMax.assert(&u16::MAX));
+        assert!(!ExpectNotMax.assert(&usize::MAX));
+
+        assert!(ExpectNotZero.assert(&-1isize));
+        assert!(!ExpectNotZero.assert(&0u32));
+
+        assert!(ExpectPositive.assert(&1i64));
+        assert!(!ExpectPositive.assert(&0u8));
+        assert!(!ExpectPositive.assert(&-1i16));
+    }
+
+    #[test]
+    fn ranges() {
+        assert!(ExpectInRange(0..4).assert(&3i32));
+        assert!(!ExpectInRange(0..4).assert(&4i32));
+        assert!(ExpectInRange(1..=u64::MAX).assert(&u64::MAX));
+        assert!(!ExpectInRange(..0isize).assert(&0));
+    }
+
+    #[test]
+    fn pointers() {
+        let mut value = 0u8;
+
+        assert!(ExpectNonNullPtr.assert(&(&mut value as *mut u8)));
+        assert!(!ExpectNonNullPtr.assert(&(null_mut::<u8>() as *const u8)));
+
+        assert!(ExpectNotMapFailed.assert(&(null_mut::<c_void>())));
+        assert!(!ExpectNotMapFailed.assert(&MAP_FAILED));
+    }
+
+    #[test]
+    fn results() {
+        assert!(ExpectOk.assert(&Ok::<_, ()>(1)));
+        assert!(!ExpectOk.assert(&Err::<(), _>(1)));
+
+        assert!(ExpectDefault.assert(&0u64));
+        assert!(!ExpectDefault.assert(&-1i32));
+    }
+
+    #[test]
+    fn errnos() {
+        let expect = ExpectErrnoIn(ExpectDefault, &[EEXIST]);
+
+        assert!(expect.assert_errno(&0, ENOENT));
+        assert!(expect.assert_errno(&-1, EEXIST));
+        assert!(!expect.assert_errno(&-1, ENOENT));
+        assert!(!expect.assert(&-1));
+    }
+}
@@ -1,26 +1,79 @@
-use clap::Parser;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, ValueEnum};
 use futures::future::select_all;
-use libbpf_rs::{Link, Map, Object};
-use prometheus::{Encoder, IntCounter, Opts, Registry};
-use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use libbpf_rs::btf::types::{DataSec, Var};
+use libbpf_rs::libbpf_sys::{bpf_map_batch_opts, bpf_map_lookup_batch};
+use libbpf_rs::{Btf, Link, Map, MapHandle, Object, OpenObject, Program};
+use prometheus::{Encoder, IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::convert::Infallible;
+use std::ffi::c_void;
 use std::fmt::{Debug, Display, Formatter};
 use std::future::Future;
-use std::net::{Ipv4Addr, Ipv6Addr};
-use std::path::Path;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::ops::AddAssign;
+use std::os::fd::{AsFd, AsRawFd};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
-use tokio::io::AsyncReadExt;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::join;
+use tokio::net::UnixListener;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::Mutex;
 use tokio::task::JoinHandle;
+use warp::http::StatusCode;
 use warp::Filter;
-use xdp::ringbuf::Ringbuf;
-use xdp::utility::{ifindex, split_array};
+use xdp::metrics::RingbufSampler;
+use xdp::netlink::{LinkEvent, LinkInfo, LinkWatcher, Netlink, XdpMode, XdpOptions};
+use xdp::perfbuf::Perfbuf;
+use xdp::ringbuf::{RecordSource, Ringbuf, RingbufRecord, RingbufStats};
+use xdp::topk::SpaceSaving;
+use xdp::utility::split_array;
 
 type Interface = u32;
 
+/// Maximum number of records drained from the ring buffer at once
+const RINGBUF_BATCH: usize = 4096;
+
+/// Number of sources read from the aggregation map at once, unless a hash bucket holds more
+const AGGREGATE_BATCH: usize = 1024;
+
+/// Number of sources returned by `/api/top` unless a limit is given
+const TOP_DEFAULT_LIMIT: usize = 10;
+
+/// Severity of a status message
+#[derive(Debug, Clone, Copy)]
+enum Level {
+    Info,
+    Warn,
+    Error,
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Level::Info => f.write_str("info"),
+            Level::Warn => f.write_str("warn"),
+            Level::Error => f.write_str("error"),
+        }
+    }
+}
+
+/// Reports a status message on stderr, e.g. `status!(Info, "attached to {}", name)`. All status
+/// output goes through here, so it's formatted the same way.
+macro_rules! status {
+    ($level:ident, $($arg:tt)*) => {
+        eprintln!("pacer {}: {}", Level::$level, format_args!($($arg)*))
+    };
+}
+
 #[repr(C)]
 #[derive(Debug)]
 enum AddrType {
@@ -28,16 +81,24 @@ enum AddrType {
     IPv6,
 }
 
-#[derive(Debug, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
 enum AddressType {
     Ipv4(Ipv4Addr),
     Ipv6(Ipv6Addr),
 }
 
-#[derive(Debug, Hash, PartialEq, Eq)]
+/// A source as told apart by the configured `FlowKey`. Fields that are not part of the key
+/// are `None`.
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
 struct Address {
     ifindex: Interface,
     address: AddressType,
+    /// L4 protocol number
+    proto: Option<u8>,
+    /// Ports are zero unless the protocol is TCP or UDP
+    sport: Option<u16>,
+    destination: Option<AddressType>,
+    dport: Option<u16>,
 }
 
 impl Display for AddressType {
@@ -49,30 +110,151 @@ impl Display for AddressType {
     }
 }
 
+impl AddressType {
+    fn from_octets(kind: &AddrType, octets: [u8; 16]) -> Self {
+        match kind {
+            AddrType::IPV4 => AddressType::Ipv4(<Ipv4Addr as From<[u8; 4]>>::from(
+                octets[0..4].try_into().unwrap(),
+            )),
+            AddrType::IPv6 => AddressType::Ipv6(<Ipv6Addr as From<[u8; 16]>>::from(octets)),
+        }
+    }
+}
+
 impl Address {
-    fn from_octets(octets: [u8; 24]) -> Self {
-        let (ifindex, octets): ([u8; 4], [u8; 20]) = split_array(octets);
+    /// Calls `f` with the values of the labels named by `FlowKey::labels`
+    fn with_labels<T, F>(&self, f: F) -> T
+    where
+        F: FnOnce(&[&str]) -> T,
+    {
+        let mut labels = vec![self.address.to_string(), self.ifindex.to_string()];
+        labels.extend(self.proto.map(|proto| proto.to_string()));
+        labels.extend(self.sport.map(|port| port.to_string()));
+        labels.extend(self.destination.as_ref().map(|address| address.to_string()));
+        labels.extend(self.dport.map(|port| port.to_string()));
+
+        let labels: Vec<&str> = labels.iter().map(String::as_str).collect();
+        f(&labels)
+    }
+
+    /// Drops the fields that are not part of `key`
+    fn reduce(mut self, key: FlowKey) -> Self {
+        if key != FlowKey::FiveTuple {
+            self.sport = None;
+            self.destination = None;
+            self.dport = None;
+        }
+
+        if key == FlowKey::Source {
+            self.proto = None;
+        }
+
+        self
+    }
+
+    /// Size of a key in the aggregation map
+    const SIZE: usize = 48;
+
+    /// Decodes a key of the aggregation map
+    fn decode(bytes: &[u8]) -> xdp::Result<Self> {
+        let octets = bytes.try_into().map_err(|_| xdp::Error::RecordSize {
+            expected: Self::SIZE,
+            found: bytes.len(),
+        })?;
+
+        Address::from_octets(octets)
+    }
+
+    fn from_octets(octets: [u8; 48]) -> xdp::Result<Self> {
+        let (ifindex, octets): ([u8; 4], [u8; 44]) = split_array(octets);
         let ifindex = u32::from_le_bytes(ifindex);
 
-        let (kind, octets): ([u8; 4], [u8; 16]) = split_array(octets);
+        let (kind, octets): ([u8; 4], [u8; 40]) = split_array(octets);
         let kind = u32::from_le_bytes(kind);
 
         let kind = match kind {
             0 => AddrType::IPV4,
             1 => AddrType::IPv6,
-            _ => unreachable!("unknown address type"),
+            kind => {
+                return Err(xdp::Error::InvalidRecord(format!(
+                    "unknown address type {}",
+                    kind
+                )))
+            }
         };
 
-        let address = match kind {
-            AddrType::IPV4 => AddressType::Ipv4(<Ipv4Addr as From<[u8; 4]>>::from(
-                octets[0..4].try_into().unwrap(),
-            )),
-            AddrType::IPv6 => AddressType::Ipv6(<Ipv6Addr as From<[u8; 16]>>::from(
-                octets[0..16].try_into().unwrap(),
-            )),
-        };
+        let (source, octets): ([u8; 16], [u8; 24]) = split_array(octets);
+        let (destination, octets): ([u8; 16], [u8; 8]) = split_array(octets);
+        let (sport, octets): ([u8; 2], [u8; 6]) = split_array(octets);
+        let (dport, octets): ([u8; 2], [u8; 4]) = split_array(octets);
 
-        Address { ifindex, address }
+        Ok(Address {
+            ifindex,
+            address: AddressType::from_octets(&kind, source),
+            proto: Some(octets[0]),
+            sport: Some(u16::from_le_bytes(sport)),
+            destination: Some(AddressType::from_octets(&kind, destination)),
+            dport: Some(u16::from_le_bytes(dport)),
+        })
+    }
+}
+
+/// The source of a packet and the length of its frame, as recorded by the XDP program
+#[derive(Debug)]
+struct Packet {
+    address: Address,
+    len: u32,
+}
+
+impl Packet {
+    fn from_octets(octets: [u8; 52]) -> xdp::Result<Self> {
+        let (address, len): ([u8; 48], [u8; 4]) = split_array(octets);
+
+        Ok(Packet {
+            address: Address::from_octets(address)?,
+            len: u32::from_le_bytes(len),
+        })
+    }
+}
+
+impl RingbufRecord for Packet {
+    const SIZE: usize = Address::SIZE + 4;
+
+    fn decode(bytes: &[u8]) -> xdp::Result<Self> {
+        let octets = bytes.try_into().map_err(|_| xdp::Error::RecordSize {
+            expected: Self::SIZE,
+            found: bytes.len(),
+        })?;
+
+        Packet::from_octets(octets)
+    }
+}
+
+/// Packets and bytes seen from a source
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Counts {
+    packets: u64,
+    bytes: u64,
+}
+
+impl Counts {
+    /// Returns what the counts grew by since `last`. Counts that went backwards were reset in
+    /// the meantime, so they grew by all of their value.
+    fn since(self, last: Counts) -> Counts {
+        match self.packets >= last.packets && self.bytes >= last.bytes {
+            true => Counts {
+                packets: self.packets - last.packets,
+                bytes: self.bytes - last.bytes,
+            },
+            false => self,
+        }
+    }
+}
+
+impl AddAssign for Counts {
+    fn add_assign(&mut self, other: Counts) {
+        self.packets += other.packets;
+        self.bytes += other.bytes;
     }
 }
 
@@ -84,150 +266,888 @@ struct Args {
     #[arg(default_value = "bpf/pacer_kern.o")]
     bpf_prog: String,
 
+    /// Interfaces to attach to. Supports `*` and `?` wildcards, e.g. `veth*`. Interfaces that
+    /// appear later are attached to as well.
     #[arg(long)]
     interfaces: Vec<String>,
+
+    /// How packets are handed over from the XDP program
+    #[arg(long, value_enum, default_value_t = Mode::Ringbuf)]
+    mode: Mode,
+
+    /// How the XDP program is attached to interfaces. Programs attached in an explicit mode are
+    /// detached when pacer stops, but stay attached if it's killed.
+    #[arg(long, value_enum, default_value_t = AttachMode::Auto)]
+    attach_mode: AttachMode,
+
+    /// Scans the counters of the aggregate mode every given number of milliseconds
+    #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+    aggregate_interval: u64,
+
+    /// Pins the record map to bpffs, so that other processes can consume it
+    #[arg(long)]
+    pin: Option<PathBuf>,
+
+    /// Exports the fill level of the ring buffer every given number of milliseconds
+    #[arg(long)]
+    ringbuf_sample_interval: Option<u64>,
+
+    /// Fields of a packet that sources are told apart by
+    #[arg(long, value_enum, default_value_t = FlowKey::Source)]
+    key: FlowKey,
+
+    /// Only tracks the given number of heaviest sources, in bounded memory. Estimates are exported
+    /// with their error, and per-interface totals stay exact.
+    #[arg(long)]
+    top_k: Option<NonZeroUsize>,
+
+    /// Forgets sources that weren't seen for the given number of seconds
+    #[arg(long, default_value_t = 60 * 60 * 24 * 7, conflicts_with = "top_k")]
+    retention: u64,
+
+    /// Looks for sources to forget every given number of seconds
+    #[arg(
+        long,
+        default_value_t = 5,
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with = "top_k"
+    )]
+    sweep_interval: u64,
+
+    /// Tracks at most the given number of sources, and forgets the least recently seen one to
+    /// make room for a new one
+    #[arg(long, conflicts_with = "top_k")]
+    max_sources: Option<NonZeroUsize>,
+
+    /// Reports the consumer as stalled on `/healthz` if it made no progress for the given number
+    /// of seconds. Must be longer than the aggregate interval.
+    #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    stall_timeout: u64,
+
+    /// Address of the admin HTTP server, either `host:port` or `unix:/path/to/socket`
+    #[arg(long, default_value = "0.0.0.0:3030")]
+    listen: Listen,
+}
+
+impl Args {
+    /// Checks constraints between arguments that can't be declared on the arguments themselves
+    fn validate(self) -> Result<Self, clap::Error> {
+        // Scans are the only progress in the aggregate mode, so the health check would flap
+        if self.mode == Mode::Aggregate
+            && self.stall_timeout.saturating_mul(1000) <= self.aggregate_interval
+        {
+            return Err(Args::command().error(
+                ErrorKind::ArgumentConflict,
+                "--stall-timeout must be longer than --aggregate-interval",
+            ));
+        }
+
+        Ok(self)
+    }
+}
+
+/// Where the admin HTTP server listens
+#[derive(Clone, Debug)]
+enum Listen {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for Listen {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) if !path.is_empty() => Ok(Listen::Unix(path.into())),
+            Some(_) => Err("missing socket path".to_string()),
+            None => s
+                .parse()
+                .map(Listen::Tcp)
+                .map_err(|err| format!("invalid address {:?}: {}", s, err)),
+        }
+    }
+}
+
+/// State reported by `/healthz`
+#[derive(Debug)]
+struct Health {
+    /// Number of interfaces the program is attached to
+    attached: AtomicUsize,
+    /// Milliseconds after `start` at which the consumer last made progress
+    progress: AtomicU64,
+    start: Instant,
+    /// How long the consumer may go without progress until it counts as stalled
+    stall_timeout: Duration,
+}
+
+impl Health {
+    fn new(stall_timeout: Duration) -> Self {
+        Health {
+            attached: AtomicUsize::new(0),
+            progress: AtomicU64::new(0),
+            start: Instant::now(),
+            stall_timeout,
+        }
+    }
+
+    /// Records that the consumer drained records, or found nothing to drain
+    fn progress(&self) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.progress.store(elapsed, Ordering::Relaxed);
+    }
+
+    /// Returns whether the consumer made no progress within the stall timeout
+    fn stalled(&self) -> bool {
+        let last = Duration::from_millis(self.progress.load(Ordering::Relaxed));
+
+        self.start.elapsed().saturating_sub(last) > self.stall_timeout
+    }
+}
+
+/// A source as returned by `/api/top`
+#[derive(Serialize, Debug)]
+struct TopEntry {
+    address: String,
+    ifindex: Interface,
+    /// Only if part of the key, like the other fields of the flow
+    #[serde(skip_serializing_if = "Option::is_none")]
+    proto: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sport: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    destination: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dport: Option<u16>,
+    /// Upper bound of the packets in heavy-hitter mode
+    packets: u64,
+    /// Maximum overestimation of `packets`, only in heavy-hitter mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<u64>,
+    /// Only when all sources are tracked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes: Option<u64>,
+    /// Seconds since the epoch, only when all sources are tracked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_seen: Option<u64>,
+}
+
+impl TopEntry {
+    fn new(address: &Address, packets: u64) -> Self {
+        TopEntry {
+            address: address.address.to_string(),
+            ifindex: address.ifindex,
+            proto: address.proto,
+            sport: address.sport,
+            destination: address.destination.as_ref().map(ToString::to_string),
+            dport: address.dport,
+            packets,
+            error: None,
+            bytes: None,
+            last_seen: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct TopQuery {
+    limit: Option<usize>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    /// One record per packet through a BPF ring buffer
+    Ringbuf,
+    /// One record per packet through a perf event array, for kernels without ring buffers
+    Perf,
+    /// Counts per source in a per-CPU LRU hash, which is scanned periodically
+    Aggregate,
+}
+
+/// Fields that tell sources apart. The discriminants match `enum key` of the XDP program.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum FlowKey {
+    /// Source address
+    Source = 0,
+    /// Source address and L4 protocol
+    SourceProto,
+    /// Source and destination address and port, and L4 protocol
+    FiveTuple,
+}
+
+impl FlowKey {
+    /// Returns the names of the labels of per-source metrics
+    fn labels(&self) -> &'static [&'static str] {
+        match self {
+            FlowKey::Source => &["address", "ifindex"],
+            FlowKey::SourceProto => &["address", "ifindex", "proto"],
+            FlowKey::FiveTuple => &[
+                "address",
+                "ifindex",
+                "proto",
+                "sport",
+                "destination",
+                "dport",
+            ],
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum AttachMode {
+    /// Driver mode if the driver supports it, SKB mode otherwise. Attached through a BPF link,
+    /// which the kernel removes once pacer exits.
+    Auto,
+    /// Generic XDP, works with every driver
+    Skb,
+    /// Native XDP
+    Driver,
+    /// Offloaded to the NIC
+    Hardware,
+}
+
+impl AttachMode {
+    /// Returns the mode to attach in through netlink, or `None` to let the kernel pick one
+    fn xdp_mode(&self) -> Option<XdpMode> {
+        match self {
+            AttachMode::Auto => None,
+            AttachMode::Skb => Some(XdpMode::Skb),
+            AttachMode::Driver => Some(XdpMode::Driver),
+            AttachMode::Hardware => Some(XdpMode::Hardware),
+        }
+    }
+}
+
+impl Mode {
+    fn prog(&self) -> &'static str {
+        match self {
+            Mode::Ringbuf => "xdp_pacer",
+            Mode::Perf => "xdp_pacer_perf",
+            Mode::Aggregate => "xdp_pacer_aggregate",
+        }
+    }
+
+    fn map(&self) -> &'static str {
+        match self {
+            Mode::Ringbuf => "packets",
+            Mode::Perf => "packets_perf",
+            Mode::Aggregate => "sources",
+        }
+    }
 }
 
 struct Bpf {
     object: Object,
-    links: Vec<Link>,
+    netlink: Netlink,
+    /// Interfaces the program is attached to
+    links: HashMap<u32, Attachment>,
+    /// Interfaces the program failed to attach to, with their flags at the time
+    failed: HashMap<u32, u32>,
+    mode: Mode,
+    attach_mode: AttachMode,
+    pinned: Option<PathBuf>,
+}
+
+/// How the program is attached to an interface
+enum Attachment {
+    /// Through a BPF link, which the kernel removes once pacer exits, however it exits
+    Link(Link),
+    /// Through netlink in an explicit mode. It stays attached until it's detached.
+    Netlink(XdpMode),
 }
 
 impl Drop for Bpf {
     fn drop(&mut self) {
-        for link in &self.links {
-            link.detach().unwrap()
+        // Interfaces that went away in the meantime were already detached by the kernel
+        for (index, attachment) in std::mem::take(&mut self.links) {
+            let _ = self.detach_xdp(index, attachment);
+        }
+
+        // Panicking while unwinding would abort, so failures are only reported
+        if let Some(path) = self.pinned.take() {
+            let unpinned = match self.object.map_mut(self.mode.map()) {
+                Some(map) => map.unpin(&path).map_err(|err| err.to_string()),
+                None => Err(format!("no map named {}", self.mode.map())),
+            };
+
+            if let Err(err) = unpinned {
+                status!(Error, "unable to unpin {}: {}", path.display(), err);
+            }
         }
     }
 }
 
 impl Bpf {
-    fn new<P>(path: P) -> Self
+    fn new<P>(path: P, mode: Mode, attach_mode: AttachMode, key: FlowKey) -> Self
     where
         P: AsRef<Path>,
     {
         let mut builder = libbpf_rs::ObjectBuilder::default();
         let open = builder.open_file(path).expect("unable to open object");
+
+        // libbpf fixed up the offsets of the variables while opening the object. Only the
+        // aggregate mode needs one, so a missing BTF is reported there.
+        let ptr = open.take_ptr();
+        let key_offset = Btf::from_bpf_object(unsafe { ptr.as_ref() })
+            .ok()
+            .flatten()
+            .and_then(|btf| rodata_offset(&btf, "aggregate_key"));
+        let mut open = unsafe { OpenObject::from_ptr(ptr) }.expect("unable to open object");
+
+        // Programs and maps of other modes are neither loaded nor created, so that we don't
+        // depend on map types the kernel might not support
+        for other in Mode::value_variants()
+            .iter()
+            .filter(|other| **other != mode)
+        {
+            open.prog_mut(other.prog())
+                .expect("unable to find prog")
+                .set_autoload(false)
+                .expect("unable to disable prog");
+
+            open.map_mut(other.map())
+                .expect("unable to find map")
+                .set_autocreate(false)
+                .expect("unable to disable map");
+        }
+
+        // The aggregate mode counts by the same key in the kernel
+        if mode == Mode::Aggregate {
+            let offset = key_offset.expect("unable to find aggregate_key");
+            let rodata = open
+                .maps_iter_mut()
+                .find(|map| map.name().is_ok_and(|name| name.ends_with(".rodata")))
+                .expect("unable to find read-only data");
+            rodata
+                .initial_value_mut()
+                .expect("unable to access read-only data")[offset..][..4]
+                .copy_from_slice(&(key as u32).to_ne_bytes());
+        }
+
         let object = open.load().expect("unable to load object");
 
         Bpf {
             object,
-            links: vec![],
+            netlink: Netlink::new().expect("unable to open netlink socket"),
+            links: HashMap::new(),
+            failed: HashMap::new(),
+            mode,
+            attach_mode,
+            pinned: None,
         }
     }
 
-    fn attach(&mut self, interfaces: Vec<String>) {
+    fn pin(&mut self, path: PathBuf) {
+        self.object
+            .map_mut(self.mode.map())
+            .expect("unable to load map")
+            .pin(&path)
+            .expect("unable to pin map");
+
+        self.pinned = Some(path);
+    }
+
+    /// Attaches the program to an interface, unless it or another program is already attached.
+    /// A failed attach is only retried once the interface flags change, not on every update.
+    fn attach(&mut self, link: &LinkInfo) {
+        if self.links.contains_key(&link.index())
+            || self.failed.get(&link.index()) == Some(&link.flags())
+        {
+            return;
+        }
+
         let prog = self
             .object
-            .prog_mut("xdp_pacer")
+            .prog_mut(self.mode.prog())
             .expect("unable to load prog");
+        let prog_id = Program::get_id_by_fd(prog.as_fd()).expect("unable to get prog id");
 
-        for interface in interfaces {
-            self.links.push(
-                prog.attach_xdp(ifindex(interface).expect("no interface found") as i32)
-                    .expect("unable to attach program"),
-            )
+        let programs = link.xdp_programs();
+        let mode = self.attach_mode.xdp_mode();
+
+        // We attached it ourselves, e.g. before the interface was renamed back and forth
+        if let Some(mode) = mode.filter(|mode| programs.get(*mode) == Some(prog_id)) {
+            self.links.insert(link.index(), Attachment::Netlink(mode));
+            return;
         }
+
+        if !programs.is_empty() {
+            status!(
+                Warn,
+                "XDP programs {:?} already attached to {}",
+                programs,
+                link.name()
+            );
+        }
+
+        // Programs of other processes are never replaced. A BPF link fails if any program is
+        // attached, the netlink request only if one is attached in the same mode.
+        let attached = match mode {
+            None => prog
+                .attach_xdp(link.index() as i32)
+                .map(Attachment::Link)
+                .map_err(xdp::Error::from),
+            Some(mode) => {
+                let options = XdpOptions::new().mode(mode).if_noexist();
+
+                self.netlink
+                    .attach_xdp(link.index(), prog.as_fd(), options)
+                    .map(|()| Attachment::Netlink(mode))
+            }
+        };
+
+        match attached {
+            Ok(attachment) => {
+                status!(Info, "attached to {}", link.name());
+                self.failed.remove(&link.index());
+                self.links.insert(link.index(), attachment);
+            }
+            Err(err) => {
+                status!(Error, "unable to attach to {}: {}", link.name(), err);
+                self.failed.insert(link.index(), link.flags());
+            }
+        }
+    }
+
+    /// Detaches from an interface that no longer matches, or forgets one that went away
+    fn detach(&mut self, link: &LinkInfo) {
+        self.failed.remove(&link.index());
+
+        if let Some(attachment) = self.links.remove(&link.index()) {
+            let _ = self.detach_xdp(link.index(), attachment);
+            status!(Info, "detached from {}", link.name());
+        }
+    }
+
+    /// Detaches the program. Programs attached through netlink are only detached unless they
+    /// were replaced by another one in the meantime.
+    fn detach_xdp(&mut self, index: u32, attachment: Attachment) -> xdp::Result<()> {
+        let mode = match attachment {
+            Attachment::Link(link) => return Ok(link.detach()?),
+            Attachment::Netlink(mode) => mode,
+        };
+
+        let prog = self
+            .object
+            .prog(self.mode.prog())
+            .expect("unable to load prog");
+
+        let options = XdpOptions::new().mode(mode).replace(prog.as_fd());
+        self.netlink.detach_xdp(index, options)
     }
 
-    fn ringbuf(&self) -> &Map {
-        self.object.map("packets").expect("unable to load map")
+    fn map(&self) -> &Map {
+        self.object
+            .map(self.mode.map())
+            .expect("unable to load map")
     }
 }
 
+/// How sources are tracked
 #[derive(Debug)]
-struct Log {
-    inner: Arc<Mutex<HashMap<Address, (u64, SystemTime)>>>,
-    task: JoinHandle<()>,
+enum Sources {
+    /// Every source, until it wasn't seen for a while
+    Exact(Recent),
+    /// Only the heaviest sources
+    Top(SpaceSaving<Address>),
 }
 
-impl Default for Log {
-    fn default() -> Self {
-        let arc = Default::default();
-        Log {
-            inner: Arc::clone(&arc),
-            task: tokio::task::spawn(async move {
-                loop {
-                    {
-                        let mut lock = arc.lock().await;
-                        let now = SystemTime::now();
-
-                        lock.retain(|_, (_, updated)| {
-                            now.duration_since(*updated).unwrap()
-                                < Duration::from_secs(60 * 60 * 24 * 7)
-                        });
-                    }
+/// Sources ordered by when they were last seen, so that the least recently seen ones can be
+/// forgotten first. Times are monotonic, so wall clock steps don't affect retention.
+#[derive(Debug)]
+struct Recent {
+    sources: HashMap<Address, Seen>,
+    /// Sources by the sequence number of their last sighting, oldest first
+    order: BTreeMap<u64, Address>,
+    next: u64,
+    retention: Duration,
+    max_sources: Option<NonZeroUsize>,
+    packets: IntCounterVec,
+    bytes: IntCounterVec,
+    evicted: IntCounterVec,
+}
 
-                    tokio::time::sleep(Duration::from_secs(5)).await;
-                }
-            }),
+#[derive(Debug)]
+struct Seen {
+    packets: IntCounter,
+    bytes: IntCounter,
+    at: Instant,
+    order: u64,
+}
+
+impl Recent {
+    /// Counts packets and bytes of a source, and makes room for it first if it's new and the log
+    /// is full
+    fn tick(&mut self, address: Address, counts: Counts, now: Instant) {
+        let order = self.next;
+        self.next += 1;
+
+        if let Some(seen) = self.sources.get_mut(&address) {
+            self.order.remove(&seen.order);
+            self.order.insert(order, address);
+
+            seen.packets.inc_by(counts.packets);
+            seen.bytes.inc_by(counts.bytes);
+            seen.at = now;
+            seen.order = order;
+
+            return;
+        }
+
+        if let Some(max) = self.max_sources {
+            while self.sources.len() >= max.get() {
+                self.evict_oldest("capacity");
+            }
+        }
+
+        let (packets, bytes) = address.with_labels(|labels| {
+            (
+                self.packets.with_label_values(labels),
+                self.bytes.with_label_values(labels),
+            )
+        });
+        packets.inc_by(counts.packets);
+        bytes.inc_by(counts.bytes);
+
+        self.order.insert(order, address.clone());
+        self.sources.insert(
+            address,
+            Seen {
+                packets,
+                bytes,
+                at: now,
+                order,
+            },
+        );
+    }
+
+    /// Forgets sources that weren't seen within the retention period
+    fn expire(&mut self, now: Instant) {
+        while let Some(address) = self.order.values().next() {
+            if now.duration_since(self.sources[address].at) < self.retention {
+                break;
+            }
+
+            self.evict_oldest("retention");
         }
     }
+
+    fn evict_oldest(&mut self, reason: &str) {
+        let Some((_, address)) = self.order.pop_first() else {
+            return;
+        };
+
+        // Evicted sources disappear from the metrics as well
+        address.with_labels(|labels| {
+            let _ = self.packets.remove_label_values(labels);
+            let _ = self.bytes.remove_label_values(labels);
+        });
+
+        self.sources.remove(&address);
+        self.evicted.with_label_values(&[reason]).inc();
+    }
+}
+
+#[derive(Debug)]
+struct Log {
+    inner: Arc<Mutex<Sources>>,
+    /// Fields that tell sources apart
+    key: FlowKey,
+    /// Estimated packets of the heaviest sources and their error
+    top_packets: IntGaugeVec,
+    top_error: IntGaugeVec,
+    /// Exact packets and bytes per interface, in both modes
+    interface_packets: IntCounterVec,
+    interface_bytes: IntCounterVec,
+    /// Sources that were forgotten, by reason
+    evicted: IntCounterVec,
+    /// Records and map keys that couldn't be decoded
+    invalid: IntCounter,
+    registry: Registry,
+    /// Sweeps sources that weren't seen for a while
+    task: Option<JoinHandle<()>>,
 }
 
 impl Drop for Log {
     fn drop(&mut self) {
-        self.task.abort();
+        if let Some(task) = &self.task {
+            task.abort();
+        }
     }
 }
 
 impl Log {
-    async fn tick(&self, address: Address) {
-        let mut lock = self.inner.lock().await;
+    /// Tracks all sources as configured, or only the `top_k` heaviest ones
+    fn new(args: &Args) -> Self {
+        let source_labels = args.key.labels();
+
+        let packets =
+            IntCounterVec::new(Opts::new("packets", "Number of packets"), source_labels).unwrap();
+        let bytes = IntCounterVec::new(
+            Opts::new("bytes", "Number of bytes, counting whole frames"),
+            source_labels,
+        )
+        .unwrap();
+        let top_packets = IntGaugeVec::new(
+            Opts::new(
+                "packets_top",
+                "Upper bound of packets of the heaviest sources",
+            ),
+            source_labels,
+        )
+        .unwrap();
+        let top_error = IntGaugeVec::new(
+            Opts::new("packets_top_error", "Maximum overestimation of packets_top"),
+            source_labels,
+        )
+        .unwrap();
+        let interface_packets = IntCounterVec::new(
+            Opts::new("interface_packets", "Number of packets per interface"),
+            &["ifindex"],
+        )
+        .unwrap();
+        let interface_bytes = IntCounterVec::new(
+            Opts::new("interface_bytes", "Number of bytes per interface"),
+            &["ifindex"],
+        )
+        .unwrap();
+        let evicted = IntCounterVec::new(
+            Opts::new("sources_evicted", "Number of sources that were forgotten"),
+            &["reason"],
+        )
+        .unwrap();
+        let invalid = IntCounter::with_opts(Opts::new(
+            "invalid_records",
+            "Number of records that couldn't be decoded",
+        ))
+        .unwrap();
+
+        let registry = Registry::new();
+        registry
+            .register(Box::new(interface_packets.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(interface_bytes.clone()))
+            .unwrap();
+        registry.register(Box::new(evicted.clone())).unwrap();
+        registry.register(Box::new(invalid.clone())).unwrap();
 
-        match lock.entry(address) {
-            Entry::Occupied(mut addr) => {
-                let (count, time) = addr.get_mut();
-                *count += 1;
-                *time = SystemTime::now();
+        let (sources, task) = match args.top_k {
+            Some(k) => {
+                registry.register(Box::new(top_packets.clone())).unwrap();
+                registry.register(Box::new(top_error.clone())).unwrap();
+
+                (
+                    Arc::new(Mutex::new(Sources::Top(SpaceSaving::new(k.get())))),
+                    None,
+                )
             }
-            Entry::Vacant(addr) => {
-                addr.insert((1, SystemTime::now()));
+            None => {
+                registry.register(Box::new(packets.clone())).unwrap();
+                registry.register(Box::new(bytes.clone())).unwrap();
+
+                let recent = Recent {
+                    sources: HashMap::new(),
+                    order: BTreeMap::new(),
+                    next: 0,
+                    retention: Duration::from_secs(args.retention),
+                    max_sources: args.max_sources,
+                    packets,
+                    bytes,
+                    evicted: evicted.clone(),
+                };
+
+                let sources = Arc::new(Mutex::new(Sources::Exact(recent)));
+                let interval = Duration::from_secs(args.sweep_interval);
+                let task = tokio::task::spawn(Self::sweep(Arc::clone(&sources), interval));
+
+                (sources, Some(task))
             }
+        };
+
+        Log {
+            inner: sources,
+            key: args.key,
+            top_packets,
+            top_error,
+            interface_packets,
+            interface_bytes,
+            evicted,
+            invalid,
+            registry,
+            task,
         }
     }
 
-    async fn registry(&self) -> Registry {
-        let registry = Registry::new();
+    /// Counts a record that couldn't be decoded. It's skipped, as one bad record shouldn't stop
+    /// the others from being counted.
+    fn skip_invalid(&self, err: xdp::Error) {
+        status!(Warn, "skipping record: {}", err);
+        self.invalid.inc();
+    }
+
+    /// Forgets sources that weren't seen within the retention period, every `interval`
+    async fn sweep(sources: Arc<Mutex<Sources>>, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+
+        loop {
+            interval.tick().await;
+
+            if let Sources::Exact(recent) = &mut *sources.lock().await {
+                recent.expire(Instant::now());
+            }
+        }
+    }
+
+    /// Adds the given packets and bytes to each source
+    async fn tick<I>(&self, addresses: I)
+    where
+        I: IntoIterator<Item = (Address, Counts)>,
+    {
+        // Counting the batch first keeps metric updates down to one per source
+        let mut batch: HashMap<Address, Counts> = HashMap::new();
+        for (address, counts) in addresses {
+            *batch.entry(address.reduce(self.key)).or_default() += counts;
+        }
+
+        let mut interfaces: HashMap<Interface, Counts> = HashMap::new();
+        for (address, counts) in batch.iter() {
+            *interfaces.entry(address.ifindex).or_default() += *counts;
+        }
 
+        for (ifindex, counts) in interfaces {
+            let ifindex = ifindex.to_string();
+
+            self.interface_packets
+                .with_label_values(&[&ifindex])
+                .inc_by(counts.packets);
+            self.interface_bytes
+                .with_label_values(&[&ifindex])
+                .inc_by(counts.bytes);
+        }
+
+        let mut lock = self.inner.lock().await;
+        let now = Instant::now();
+
+        match &mut *lock {
+            Sources::Exact(recent) => {
+                for (address, counts) in batch {
+                    recent.tick(address, counts, now);
+                }
+            }
+            Sources::Top(top) => {
+                // Sources are ranked by packets
+                for (address, counts) in batch {
+                    if let Some(evicted) = top.insert(address.clone(), counts.packets) {
+                        self.evicted.with_label_values(&["top_k"]).inc();
+
+                        evicted.with_labels(|labels| {
+                            let _ = self.top_packets.remove_label_values(labels);
+                            let _ = self.top_error.remove_label_values(labels);
+                        });
+                    }
+
+                    let estimate = top.get(&address).unwrap();
+
+                    address.with_labels(|labels| {
+                        self.top_packets
+                            .with_label_values(labels)
+                            .set(estimate.count as i64);
+                        self.top_error
+                            .with_label_values(labels)
+                            .set(estimate.error as i64);
+                    });
+                }
+            }
+        }
+    }
+
+    /// Returns the sources with the most packets, busiest first
+    async fn top(&self, limit: usize) -> Vec<TopEntry> {
         let lock = self.inner.lock().await;
 
-        for (address, (packets, _)) in lock.iter() {
-            let opts = Opts::new("packets", "Number of packets")
-                .const_label("address", format!("{}", address.address))
-                .const_label("ifindex", format!("{}", address.ifindex));
+        match &*lock {
+            Sources::Exact(recent) => {
+                // Sightings are monotonic, so they are translated to the wall clock of now
+                let now = SystemTime::now();
+
+                let mut top: Vec<_> = recent
+                    .sources
+                    .iter()
+                    .map(|(address, seen)| TopEntry {
+                        bytes: Some(seen.bytes.get()),
+                        last_seen: Some(
+                            (now - seen.at.elapsed())
+                                .duration_since(UNIX_EPOCH)
+                                .unwrap_or_default()
+                                .as_secs(),
+                        ),
+                        ..TopEntry::new(address, seen.packets.get())
+                    })
+                    .collect();
 
-            let counter = IntCounter::with_opts(opts).unwrap();
-            counter.inc_by(*packets);
+                top.sort_unstable_by_key(|entry| std::cmp::Reverse(entry.packets));
+                top.truncate(limit);
 
-            registry.register(Box::new(counter)).unwrap();
+                top
+            }
+            Sources::Top(top) => top
+                .top(limit)
+                .into_iter()
+                .map(|estimate| TopEntry {
+                    error: Some(estimate.error),
+                    ..TopEntry::new(estimate.key, estimate.count)
+                })
+                .collect(),
         }
+    }
 
-        registry
+    /// Returns the registry of the per-source counters, which doesn't lock the log
+    fn registry(&self) -> &Registry {
+        &self.registry
     }
 }
 
 #[tokio::main]
 async fn main() {
-    let args = Args::parse();
-    let log = Arc::new(Log::default());
+    let args = Args::parse().validate().unwrap_or_else(|err| err.exit());
+    let log = Arc::new(Log::new(&args));
+    let health = Arc::new(Health::new(Duration::from_secs(args.stall_timeout)));
 
+    // Whatever finishes first ends pacer. The others are dropped, which detaches the program
+    // from all interfaces.
     select_all(vec![
-        Box::pin(ringbuffer(&args, Arc::clone(&log))) as Pin<Box<dyn Future<Output = ()>>>,
-        Box::pin(prometheus(&args, Arc::clone(&log))),
+        Box::pin(ringbuffer(&args, Arc::clone(&log), Arc::clone(&health)))
+            as Pin<Box<dyn Future<Output = ()>>>,
+        Box::pin(admin(&args, Arc::clone(&log), Arc::clone(&health))),
+        Box::pin(shutdown()),
     ])
     .await;
 }
 
-async fn prometheus(_: &Args, log: Arc<Log>) {
-    let hello = warp::any().and_then(move || {
-        let log = log.clone();
+/// Resolves once pacer is asked to stop with SIGINT or SIGTERM
+async fn shutdown() {
+    let mut terminate = signal(SignalKind::terminate()).expect("unable to handle SIGTERM");
+
+    tokio::select! {
+        interrupt = tokio::signal::ctrl_c() => interrupt.expect("unable to handle SIGINT"),
+        _ = terminate.recv() => {}
+    }
+
+    status!(Info, "shutting down");
+}
+
+/// Serves metrics, health and the busiest sources over HTTP. Other paths get a 404.
+async fn admin(args: &Args, log: Arc<Log>, health: Arc<Health>) {
+    let metrics_log = Arc::clone(&log);
+    let metrics = warp::path!("metrics").and(warp::get()).and_then(move || {
+        let log = metrics_log.clone();
         async move {
-            let registry = log.registry().await;
             let mut buffer = Vec::<u8>::new();
 
             let encoder = prometheus::TextEncoder::new();
-            encoder.encode(&registry.gather(), &mut buffer).unwrap();
+            let mut families = log.registry().gather();
+            families.extend(prometheus::gather());
+            encoder.encode(&families, &mut buffer).unwrap();
 
             let text = String::from_utf8(buffer).unwrap();
 
@@ -235,24 +1155,654 @@ async fn prometheus(_: &Args, log: Arc<Log>) {
         }
     });
 
-    warp::serve(hello).run(([0, 0, 0, 0], 3030)).await;
+    let healthz = warp::path!("healthz").and(warp::get()).map(move || {
+        let attached = health.attached.load(Ordering::Relaxed);
+
+        let (status, text) = match (attached, !health.stalled()) {
+            (0, _) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "not attached to any interface",
+            ),
+            (_, false) => (StatusCode::SERVICE_UNAVAILABLE, "consumer stalled"),
+            _ => (StatusCode::OK, "ok"),
+        };
+
+        warp::reply::with_status(text, status)
+    });
+
+    let top = warp::path!("api" / "top")
+        .and(warp::get())
+        .and(warp::query::<TopQuery>())
+        .and_then(move |query: TopQuery| {
+            let log = log.clone();
+            async move {
+                let top = log.top(query.limit.unwrap_or(TOP_DEFAULT_LIMIT)).await;
+
+                Ok::<_, Infallible>(warp::reply::json(&top))
+            }
+        });
+
+    let routes = metrics.or(healthz).or(top);
+
+    match &args.listen {
+        Listen::Tcp(addr) => warp::serve(routes).run(*addr).await,
+        Listen::Unix(path) => {
+            // A socket left behind by an earlier run would make bind fail
+            if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                std::fs::remove_file(path).expect("unable to remove stale admin socket");
+            }
+
+            let listener = UnixListener::bind(path).expect("unable to bind admin socket");
+            let incoming = futures::stream::poll_fn(move |cx| {
+                listener
+                    .poll_accept(cx)
+                    .map(|accepted| Some(accepted.map(|(stream, _)| stream)))
+            });
+
+            warp::serve(routes).run_incoming(incoming).await
+        }
+    }
 }
 
-async fn ringbuffer(args: &Args, log: Arc<Log>) {
-    let mut bpf = Bpf::new(args.bpf_obj.clone());
+async fn ringbuffer(args: &Args, log: Arc<Log>, health: Arc<Health>) {
+    let mut bpf = Bpf::new(args.bpf_obj.clone(), args.mode, args.attach_mode, args.key);
+
+    if let Some(path) = &args.pin {
+        bpf.pin(path.clone());
+    }
+
+    match args.mode {
+        Mode::Ringbuf => {
+            // The ring buffer gets its own map fd, so that it doesn't borrow the object while
+            // the link watcher attaches to interfaces
+            let fd = bpf
+                .map()
+                .as_fd()
+                .try_clone_to_owned()
+                .expect("can't clone map fd");
+            let ringbuf = Ringbuf::from_fd(fd).expect("can't load ringbuffer");
+            let stats = ringbuf.stats();
+
+            // The sampler stops once its handle is dropped together with this future
+            let _sampler = args.ringbuf_sample_interval.map(|interval| {
+                let sampler = RingbufSampler::new(ringbuf.stats(), args.mode.map())
+                    .expect("can't create ringbuffer metrics");
+
+                sampler
+                    .register(prometheus::default_registry())
+                    .expect("can't register ringbuffer metrics");
 
-    bpf.attach(args.interfaces.clone());
+                sampler.spawn(Duration::from_millis(interval))
+            });
 
-    let mut ringbuf = Ringbuf::from_map(bpf.ringbuf()).expect("can't load ringbuffer");
+            join!(
+                consume(ringbuf, Some(stats), log, &health),
+                watch(&mut bpf, &args.interfaces, &health)
+            );
+        }
+        Mode::Perf => {
+            let perfbuf = Perfbuf::from_map(bpf.map()).expect("can't load perf buffer");
+
+            join!(
+                consume(perfbuf, None, log, &health),
+                watch(&mut bpf, &args.interfaces, &health)
+            );
+        }
+        Mode::Aggregate => {
+            let map = MapHandle::try_clone(bpf.map()).expect("can't clone map");
+            let interval = Duration::from_millis(args.aggregate_interval);
+
+            join!(
+                aggregate(map, log, &health, interval),
+                watch(&mut bpf, &args.interfaces, &health)
+            );
+        }
+    }
+}
+
+/// Attaches to interfaces matching one of `patterns` as they appear, and drops their links when
+/// they go away
+async fn watch(bpf: &mut Bpf, patterns: &[String], health: &Health) {
+    let mut watcher = LinkWatcher::new().expect("unable to watch interfaces");
 
     loop {
-        let mut data = [0u8; 24];
-        let _ = ringbuf
-            .read(&mut data)
+        let event = watcher
+            .next()
             .await
-            .expect("can't read from ringbuf");
+            .expect("unable to receive interface events");
+
+        match event {
+            LinkEvent::New(link) => {
+                let name = link.name().as_bytes();
+
+                if patterns
+                    .iter()
+                    .any(|pattern| glob(pattern.as_bytes(), name))
+                {
+                    bpf.attach(&link);
+                } else {
+                    // The interface might have been renamed
+                    bpf.detach(&link);
+                }
+            }
+            LinkEvent::Del(link) => bpf.detach(&link),
+        }
+
+        health.attached.store(bpf.links.len(), Ordering::Relaxed);
+    }
+}
+
+/// Matches a name against a shell-style pattern, where `*` matches any number of characters and
+/// `?` matches a single one
+fn glob(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob(&pattern[1..], name) || (!name.is_empty() && glob(pattern, &name[1..]))
+        }
+        (Some(b'?'), Some(_)) => glob(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => glob(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// Hands over records to the log in batches. While no records arrive, the consumer still
+/// reports progress every half stall timeout, unless `stats` shows records that are stuck.
+async fn consume<S>(mut source: S, stats: Option<RingbufStats>, log: Arc<Log>, health: &Health)
+where
+    S: RecordSource,
+{
+    let mut batch = Vec::with_capacity(RINGBUF_BATCH);
+    let idle = health.stall_timeout / 2;
+
+    loop {
+        // Records are only consumed once a drain completes, so it can be cancelled
+        let drained = tokio::time::timeout(
+            idle,
+            source.drain(RINGBUF_BATCH, |bytes| {
+                match Packet::from_padded_bytes(bytes, S::PADDING) {
+                    Ok(packet) => batch.push(packet),
+                    Err(err) => log.skip_invalid(err),
+                }
+            }),
+        )
+        .await;
+
+        match drained {
+            Ok(drained) => {
+                drained.expect("can't read records");
+            }
+            Err(_) if stats.as_ref().is_some_and(|stats| stats.pending() > 0) => continue,
+            Err(_) => {}
+        }
+
+        log.tick(batch.drain(..).map(|packet| {
+            let counts = Counts {
+                packets: 1,
+                bytes: packet.len as u64,
+            };
+
+            (packet.address, counts)
+        }))
+        .await;
+
+        health.progress();
+    }
+}
+
+/// Sums the per-CPU counters of all sources every `interval`, and adds what they grew by since
+/// the last scan to the log
+async fn aggregate(map: MapHandle, log: Arc<Log>, health: &Health, interval: Duration) {
+    let cpus = libbpf_rs::num_possible_cpus().expect("can't get number of cpus");
+    let mut previous: HashMap<Vec<u8>, Counts> = HashMap::new();
+    let mut interval = tokio::time::interval(interval);
+
+    loop {
+        interval.tick().await;
+
+        let mut current = HashMap::with_capacity(previous.len());
+        let mut batch = vec![];
+
+        for (key, counts) in lookup_sources(&map, cpus) {
+            // A source that was evicted and came back starts from zero again
+            let grown = counts.since(previous.get(&key).copied().unwrap_or_default());
+
+            if grown.packets > 0 {
+                match Address::decode(&key) {
+                    Ok(address) => batch.push((address, grown)),
+                    Err(err) => log.skip_invalid(err),
+                }
+            }
+
+            current.insert(key, counts);
+        }
+
+        previous = current;
+        log.tick(batch).await;
+
+        health.progress();
+    }
+}
+
+/// Returns the offset of a 4 byte global variable in the read-only data section
+fn rodata_offset(btf: &Btf<'_>, name: &str) -> Option<usize> {
+    let rodata: DataSec<'_> = btf.type_by_name(".rodata")?;
+
+    let info = rodata.iter().find(|info| {
+        info.size == 4
+            && btf
+                .type_by_id::<Var<'_>>(info.ty)
+                .and_then(|var| var.name())
+                .is_some_and(|var| var.to_bytes() == name.as_bytes())
+    })?;
+
+    Some(info.offset as usize)
+}
+
+/// Reads the counts of all sources in the aggregation map. Unlike walking the keys one by one,
+/// which starts over whenever the current key was evicted, batches walk the hash buckets in
+/// order, so every source is returned at most once.
+fn lookup_sources(map: &MapHandle, cpus: usize) -> Vec<(Vec<u8>, Counts)> {
+    let key_size = map.key_size() as usize;
+
+    // Per-CPU values are a packet and a byte counter, each padded to 8 bytes
+    let value_size = (map.value_size() as usize).next_multiple_of(8);
+
+    let opts = bpf_map_batch_opts {
+        sz: size_of::<bpf_map_batch_opts>() as _,
+        ..Default::default()
+    };
+
+    let mut sources = vec![];
+    let mut chunk = AGGREGATE_BATCH;
+
+    // Hash maps use the bucket index to resume a walk
+    let mut position: Option<u32> = None;
+
+    loop {
+        let mut keys = vec![0u8; chunk * key_size];
+        let mut values = vec![0u8; chunk * cpus * value_size];
+        let mut count = chunk as u32;
+        let mut next = 0u32;
+
+        let ret = unsafe {
+            bpf_map_lookup_batch(
+                map.as_fd().as_raw_fd(),
+                position.as_mut().map_or(std::ptr::null_mut(), |position| {
+                    position as *mut u32 as *mut c_void
+                }),
+                &mut next as *mut u32 as *mut c_void,
+                keys.as_mut_ptr() as *mut c_void,
+                values.as_mut_ptr() as *mut c_void,
+                &mut count,
+                &opts,
+            )
+        };
+        let errno = match ret {
+            0 => 0,
+            _ => io::Error::last_os_error()
+                .raw_os_error()
+                .unwrap_or_default(),
+        };
+
+        // A single bucket holds more sources than fit into the batch
+        if errno == libc::ENOSPC && count == 0 {
+            chunk *= 2;
+            continue;
+        }
+
+        if errno != 0 && errno != libc::ENOENT {
+            panic!(
+                "can't look up sources: {}",
+                io::Error::from_raw_os_error(errno)
+            );
+        }
+
+        for index in 0..count as usize {
+            let key = &keys[index * key_size..][..key_size];
+            let values = &values[index * cpus * value_size..][..cpus * value_size];
+
+            let mut counts = Counts::default();
+            for value in values.chunks_exact(value_size) {
+                counts += Counts {
+                    packets: u64::from_ne_bytes(value[0..8].try_into().unwrap()),
+                    bytes: u64::from_ne_bytes(value[8..16].try_into().unwrap()),
+                };
+            }
+
+            sources.push((key.to_vec(), counts));
+        }
+
+        // ENOENT marks the end of the walk, even if the last batch returned sources
+        if errno == libc::ENOENT {
+            return sources;
+        }
+
+        position = Some(next);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        glob, Address, AddressType, Args, Counts, FlowKey, Health, Listen, Packet, Recent,
+    };
+    use clap::{Parser, ValueEnum};
+    use prometheus::{IntCounterVec, Opts};
+    use std::collections::{BTreeMap, HashMap};
+    use std::net::SocketAddr;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::num::NonZeroUsize;
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+    use xdp::ringbuf::RingbufRecord;
+
+    /// Returns a record as written by the XDP program
+    fn record(kind: u32, source: &[u8], destination: &[u8], len: u32) -> Vec<u8> {
+        let mut record = vec![];
+        record.extend(3u32.to_le_bytes());
+        record.extend(kind.to_le_bytes());
+        record.extend(source);
+        record.resize(24, 0);
+        record.extend(destination);
+        record.resize(40, 0);
+        record.extend(1234u16.to_le_bytes());
+        record.extend(80u16.to_le_bytes());
+        record.extend([6, 0, 0, 0]);
+        record.extend(len.to_le_bytes());
+
+        record
+    }
+
+    fn source(last: u8) -> Address {
+        Address {
+            ifindex: 1,
+            address: AddressType::Ipv4(Ipv4Addr::new(10, 0, 0, last)),
+            proto: None,
+            sport: None,
+            destination: None,
+            dport: None,
+        }
+    }
+
+    fn recent(retention: Duration, max_sources: Option<usize>) -> Recent {
+        let labels = FlowKey::Source.labels();
+        let counter = |name: &str, labels: &[&str]| {
+            IntCounterVec::new(Opts::new(name, name), labels).unwrap()
+        };
+
+        Recent {
+            sources: HashMap::new(),
+            order: BTreeMap::new(),
+            next: 0,
+            retention,
+            max_sources: max_sources.and_then(NonZeroUsize::new),
+            packets: counter("packets", labels),
+            bytes: counter("bytes", labels),
+            evicted: counter("evicted", &["reason"]),
+        }
+    }
+
+    fn tracked(recent: &Recent) -> Vec<u8> {
+        recent
+            .order
+            .values()
+            .map(|address| match address.address {
+                AddressType::Ipv4(v4) => v4.octets()[3],
+                AddressType::Ipv6(_) => unreachable!(),
+            })
+            .collect()
+    }
+
+    const ONE: Counts = Counts {
+        packets: 1,
+        bytes: 64,
+    };
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob(b"eth0", b"eth0"));
+        assert!(!glob(b"eth0", b"eth01"));
+        assert!(!glob(b"eth0", b"eth"));
+
+        assert!(glob(b"veth*", b"veth"));
+        assert!(glob(b"veth*", b"veth1a2b"));
+        assert!(!glob(b"veth*", b"eth0"));
+        assert!(glob(b"*", b""));
+        assert!(glob(b"*0", b"eth0"));
+        assert!(glob(b"e*h*0", b"ethernet0"));
+        assert!(!glob(b"e*h*0", b"ethernet1"));
+
+        assert!(glob(b"eth?", b"eth1"));
+        assert!(!glob(b"eth?", b"eth"));
+        assert!(!glob(b"eth?", b"eth10"));
+        assert!(glob(b"?*", b"a"));
+        assert!(!glob(b"?*", b""));
+    }
+
+    #[test]
+    fn listen_addresses() {
+        let tcp = |s: &str| match s.parse() {
+            Ok(Listen::Tcp(addr)) => Some(addr),
+            _ => None,
+        };
+
+        assert_eq!(
+            tcp("127.0.0.1:3030"),
+            Some(SocketAddr::from(([127, 0, 0, 1], 3030)))
+        );
+        assert_eq!(tcp("[::1]:80"), "[::1]:80".parse().ok());
+
+        match "unix:/run/pacer.sock".parse() {
+            Ok(Listen::Unix(path)) => assert_eq!(path, PathBuf::from("/run/pacer.sock")),
+            other => panic!("expected unix socket, got {:?}", other),
+        }
+
+        assert!("unix:".parse::<Listen>().is_err());
+        assert!("localhost".parse::<Listen>().is_err());
+        assert!("127.0.0.1".parse::<Listen>().is_err());
+        assert!("/run/pacer.sock".parse::<Listen>().is_err());
+    }
+
+    #[test]
+    fn stalled_without_progress() {
+        let health = Health::new(Duration::from_millis(20));
+        assert!(!health.stalled());
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(health.stalled());
+
+        health.progress();
+        assert!(!health.stalled());
+    }
+
+    #[test]
+    fn capacity_evicts_least_recently_seen() {
+        let mut recent = recent(Duration::from_secs(60), Some(2));
+        let now = Instant::now();
+
+        recent.tick(source(1), ONE, now);
+        recent.tick(source(2), ONE, now);
+        recent.tick(source(3), ONE, now);
+        assert_eq!(tracked(&recent), vec![2, 3]);
+
+        // Seeing a source again moves it to the back of the line
+        recent.tick(source(2), ONE, now);
+        recent.tick(source(4), ONE, now);
+        assert_eq!(tracked(&recent), vec![2, 4]);
+
+        assert_eq!(recent.sources[&source(2)].packets.get(), 2);
+        assert_eq!(recent.sources[&source(2)].bytes.get(), 128);
+        assert_eq!(recent.evicted.with_label_values(&["capacity"]).get(), 2);
+    }
+
+    #[test]
+    fn retention_expires_unseen_sources() {
+        let mut recent = recent(Duration::from_secs(10), None);
+        let start = Instant::now();
+
+        recent.tick(source(1), ONE, start);
+        recent.tick(source(2), ONE, start + Duration::from_secs(5));
+        recent.tick(source(3), ONE, start + Duration::from_secs(6));
+
+        // A sighting refreshes the retention
+        recent.tick(source(1), ONE, start + Duration::from_secs(8));
+
+        recent.expire(start + Duration::from_secs(15));
+        assert_eq!(tracked(&recent), vec![3, 1]);
+
+        recent.expire(start + Duration::from_secs(18));
+        assert_eq!(tracked(&recent), Vec::<u8>::new());
+        assert!(recent.sources.is_empty());
+        assert_eq!(recent.evicted.with_label_values(&["retention"]).get(), 3);
+    }
+
+    #[test]
+    fn top_k_conflicts_with_retention() {
+        assert!(Args::try_parse_from(["pacer", "--top-k", "5"]).is_ok());
+
+        for arg in ["--retention", "--sweep-interval", "--max-sources"] {
+            assert!(Args::try_parse_from(["pacer", "--top-k", "5", arg, "10"]).is_err());
+        }
+    }
+
+    #[test]
+    fn counts_since() {
+        let last = Counts {
+            packets: 10,
+            bytes: 1000,
+        };
+
+        let grown = Counts {
+            packets: 12,
+            bytes: 1128,
+        };
+        assert_eq!(
+            grown.since(last),
+            Counts {
+                packets: 2,
+                bytes: 128
+            }
+        );
+        assert_eq!(last.since(last), Counts::default());
+
+        // The source was evicted and came back, so all of its counts are new
+        assert_eq!(ONE.since(last), ONE);
+        assert_eq!(
+            Counts {
+                packets: 11,
+                bytes: 64
+            }
+            .since(last),
+            Counts {
+                packets: 11,
+                bytes: 64
+            }
+        );
+    }
+
+    #[test]
+    fn records_decode() {
+        let ipv4 = record(0, &[10, 0, 0, 1], &[192, 168, 1, 2], 1500);
+        assert_eq!(ipv4.len(), Packet::SIZE);
+
+        let packet = Packet::decode(&ipv4).unwrap();
+        assert_eq!(packet.len, 1500);
+        assert_eq!(
+            packet.address,
+            Address {
+                ifindex: 3,
+                address: AddressType::Ipv4(Ipv4Addr::new(10, 0, 0, 1)),
+                proto: Some(6),
+                sport: Some(1234),
+                destination: Some(AddressType::Ipv4(Ipv4Addr::new(192, 168, 1, 2))),
+                dport: Some(80),
+            }
+        );
+
+        // Keys of the aggregation map are records without the length
+        let key = Address::decode(&ipv4[..Address::SIZE]).unwrap();
+        assert_eq!(key, packet.address);
+
+        let source = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+        let destination = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2);
+        let ipv6 = record(1, &source.octets(), &destination.octets(), 60);
+
+        let packet = Packet::decode(&ipv6).unwrap();
+        assert_eq!(packet.address.address, AddressType::Ipv6(source));
+        assert_eq!(
+            packet.address.destination,
+            Some(AddressType::Ipv6(destination))
+        );
+        assert_eq!(packet.len, 60);
+    }
+
+    #[test]
+    fn invalid_records_are_rejected() {
+        let unknown = record(2, &[10, 0, 0, 1], &[10, 0, 0, 2], 60);
+        assert!(matches!(
+            Packet::decode(&unknown),
+            Err(xdp::Error::InvalidRecord(_))
+        ));
+
+        let ipv4 = record(0, &[10, 0, 0, 1], &[10, 0, 0, 2], 60);
+        assert!(matches!(
+            Address::decode(&ipv4),
+            Err(xdp::Error::RecordSize {
+                expected: 48,
+                found: 52
+            })
+        ));
+    }
+
+    #[test]
+    fn labels_match_values() {
+        let ipv4 = record(0, &[10, 0, 0, 1], &[192, 168, 1, 2], 1500);
+        let address = Packet::decode(&ipv4).unwrap().address;
+
+        let expected = HashMap::from([
+            ("address", "10.0.0.1"),
+            ("ifindex", "3"),
+            ("proto", "6"),
+            ("sport", "1234"),
+            ("destination", "192.168.1.2"),
+            ("dport", "80"),
+        ]);
+
+        for key in FlowKey::value_variants() {
+            let names = key.labels();
+
+            address.clone().reduce(*key).with_labels(|values| {
+                assert_eq!(names.len(), values.len(), "{:?}", key);
+
+                for (name, value) in names.iter().zip(values) {
+                    assert_eq!(expected[name], *value, "{:?}", key);
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn stall_timeout_exceeds_aggregate_interval() {
+        let parse = |args: &[&str]| {
+            Args::try_parse_from(["pacer"].iter().chain(args))
+                .unwrap()
+                .validate()
+        };
+
+        assert!(parse(&["--mode", "aggregate", "--aggregate-interval", "29999"]).is_ok());
+        assert!(parse(&["--mode", "aggregate", "--aggregate-interval", "30000"]).is_err());
+        assert!(parse(&[
+            "--mode",
+            "aggregate",
+            "--stall-timeout",
+            "1",
+            "--aggregate-interval",
+            "1000"
+        ])
+        .is_err());
 
-        let addr = Address::from_octets(data);
-        log.tick(addr).await;
+        // Records are consumed as they arrive in the other modes
+        assert!(parse(&["--aggregate-interval", "30000"]).is_ok());
     }
 }
@@ -0,0 +1,311 @@
+use crate::assert::{unsafe_no_panic, ExpectErrnoIn, ExpectNonNegative};
+use crate::{Error, Result};
+use libc::{
+    c_int, c_void, ioctl, socket, AF_INET, ENOENT, IFNAMSIZ, SIOCETHTOOL, SOCK_CLOEXEC, SOCK_DGRAM,
+};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+const ETHTOOL_GRXRINGS: u32 = 0x2d;
+const ETHTOOL_SRXCLSRLDEL: u32 = 0x31;
+const ETHTOOL_SRXCLSRLINS: u32 = 0x32;
+const ETHTOOL_GRXFHINDIR: u32 = 0x38;
+const ETHTOOL_SRXFHINDIR: u32 = 0x39;
+
+/// Lets the driver pick the location of a new rule
+const RX_CLS_LOC_ANY: u32 = 0xffffffff;
+
+const TCP_V4_FLOW: u32 = 0x01;
+const UDP_V4_FLOW: u32 = 0x02;
+const TCP_V6_FLOW: u32 = 0x05;
+const UDP_V6_FLOW: u32 = 0x06;
+
+/// Offsets of the destination port within `ethtool_tcpip4_spec` and `ethtool_tcpip6_spec`
+const TCPIP4_PDST: usize = 10;
+const TCPIP6_PDST: usize = 34;
+
+/// Configures flow steering and RSS of an interface through the ethtool ioctl
+#[derive(Debug)]
+pub struct Ethtool {
+    fd: OwnedFd,
+    interface: String,
+}
+
+/// Protocols a flow rule can match on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowProto {
+    Tcp4,
+    Udp4,
+    Tcp6,
+    Udp6,
+}
+
+/// An ntuple rule that steers packets to a destination port into an RX queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlowRule {
+    proto: FlowProto,
+    dst_port: u16,
+    queue: u32,
+    location: Option<u32>,
+}
+
+/// A flow rule that is removed from the interface once dropped
+#[derive(Debug)]
+pub struct FlowRuleGuard<'a> {
+    ethtool: &'a Ethtool,
+    location: u32,
+}
+
+/// `struct ethtool_rx_flow_spec`, with the flow unions and extensions as raw bytes
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct EthtoolRxFlowSpec {
+    flow_type: u32,
+    h_u: [u8; 52],
+    h_ext: [u8; 20],
+    m_u: [u8; 52],
+    m_ext: [u8; 20],
+    ring_cookie: u64,
+    location: u32,
+}
+
+/// `struct ethtool_rxnfc` without the trailing rule locations
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct EthtoolRxnfc {
+    cmd: u32,
+    flow_type: u32,
+    data: u64,
+    fs: EthtoolRxFlowSpec,
+    rule_cnt: u32,
+}
+
+/// `struct ifreq` with `ifr_data` as the only member of the union we use
+#[repr(C)]
+struct IfReq {
+    name: [u8; IFNAMSIZ],
+    data: *mut c_void,
+    _pad: [u8; 16],
+}
+
+impl Ethtool {
+    /// Opens a control socket for the given interface
+    pub fn new(interface: &str) -> Result<Self> {
+        if interface.len() >= IFNAMSIZ || interface.contains('\0') {
+            return Err(Error::InvalidName(interface.to_string()));
+        }
+
+        let fd = unsafe_no_panic!(socket(AF_INET, SOCK_DGRAM | SOCK_CLOEXEC, 0))
+            .expect(ExpectNonNegative, |os| Error::EthtoolSocket { os })?;
+
+        Ok(Ethtool {
+            // SAFETY: File Descriptor was properly checked
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            interface: interface.to_string(),
+        })
+    }
+
+    /// Returns the number of RX queues flow rules can steer to
+    pub fn rx_rings(&self) -> Result<u32> {
+        let mut nfc = EthtoolRxnfc::new(ETHTOOL_GRXRINGS);
+        self.ioctl(ETHTOOL_GRXRINGS, &mut nfc as *mut _ as _, &[])?;
+
+        Ok(nfc.data as u32)
+    }
+
+    /// Inserts a flow rule. The rule is removed when the returned guard is dropped.
+    pub fn add_flow_rule(&self, rule: FlowRule) -> Result<FlowRuleGuard<'_>> {
+        let mut nfc = EthtoolRxnfc::new(ETHTOOL_SRXCLSRLINS);
+        nfc.fs = rule.spec();
+
+        self.ioctl(ETHTOOL_SRXCLSRLINS, &mut nfc as *mut _ as _, &[])?;
+
+        // The driver reports back where it placed the rule
+        Ok(FlowRuleGuard {
+            ethtool: self,
+            location: nfc.fs.location,
+        })
+    }
+
+    /// Removes the flow rule at the given location. A rule that is already gone, e.g. because
+    /// the driver reset its rules, counts as removed.
+    pub fn remove_flow_rule(&self, location: u32) -> Result<()> {
+        let mut nfc = EthtoolRxnfc::new(ETHTOOL_SRXCLSRLDEL);
+        nfc.fs.location = location;
+
+        self.ioctl(ETHTOOL_SRXCLSRLDEL, &mut nfc as *mut _ as _, &[ENOENT])
+    }
+
+    /// Returns the RSS indirection table, which maps hash buckets to RX queues
+    pub fn rss_indirection(&self) -> Result<Vec<u32>> {
+        // The first call only reports the size of the table
+        let mut header = [ETHTOOL_GRXFHINDIR, 0];
+        self.ioctl(ETHTOOL_GRXFHINDIR, header.as_mut_ptr() as _, &[])?;
+
+        let mut buf = vec![0u32; 2 + header[1] as usize];
+        buf[0] = ETHTOOL_GRXFHINDIR;
+        buf[1] = header[1];
+        self.ioctl(ETHTOOL_GRXFHINDIR, buf.as_mut_ptr() as _, &[])?;
+
+        Ok(buf.split_off(2))
+    }
+
+    /// Replaces the RSS indirection table. The table must have as many entries as the one
+    /// returned by `rss_indirection`.
+    pub fn set_rss_indirection(&self, table: &[u32]) -> Result<()> {
+        let mut buf = vec![ETHTOOL_SRXFHINDIR, table.len().try_into()?];
+        buf.extend_from_slice(table);
+
+        self.ioctl(ETHTOOL_SRXFHINDIR, buf.as_mut_ptr() as _, &[])
+    }
+
+    /// Restores the default RSS indirection table of the driver
+    pub fn reset_rss_indirection(&self) -> Result<()> {
+        self.set_rss_indirection(&[])
+    }
+
+    pub fn interface(&self) -> &str {
+        &self.interface
+    }
+
+    /// Runs an ethtool command. Failures with one of the `ignored` errnos count as success.
+    fn ioctl(&self, command: u32, data: *mut c_void, ignored: &[c_int]) -> Result<()> {
+        let mut ifreq = IfReq {
+            name: [0; IFNAMSIZ],
+            data,
+            _pad: [0; 16],
+        };
+        ifreq.name[..self.interface.len()].copy_from_slice(self.interface.as_bytes());
+
+        unsafe_no_panic!(ioctl(self.fd.as_raw_fd(), SIOCETHTOOL as _, &mut ifreq)).expect(
+            ExpectErrnoIn(ExpectNonNegative, ignored),
+            |os| Error::Ethtool {
+                interface: self.interface.clone(),
+                command,
+                os,
+            },
+        )?;
+
+        Ok(())
+    }
+}
+
+impl FlowRule {
+    /// Steers packets of `proto` with destination port `dst_port` into `queue`. The driver picks
+    /// the location of the rule.
+    pub fn new(proto: FlowProto, dst_port: u16, queue: u32) -> Self {
+        FlowRule {
+            proto,
+            dst_port,
+            queue,
+            location: None,
+        }
+    }
+
+    /// Places the rule at the given location, which some drivers require
+    pub fn with_location(mut self, location: u32) -> Self {
+        self.location = Some(location);
+        self
+    }
+
+    fn spec(&self) -> EthtoolRxFlowSpec {
+        let (flow_type, pdst) = match self.proto {
+            FlowProto::Tcp4 => (TCP_V4_FLOW, TCPIP4_PDST),
+            FlowProto::Udp4 => (UDP_V4_FLOW, TCPIP4_PDST),
+            FlowProto::Tcp6 => (TCP_V6_FLOW, TCPIP6_PDST),
+            FlowProto::Udp6 => (UDP_V6_FLOW, TCPIP6_PDST),
+        };
+
+        let mut spec = EthtoolRxFlowSpec {
+            flow_type,
+            ring_cookie: self.queue as u64,
+            location: self.location.unwrap_or(RX_CLS_LOC_ANY),
+            ..Default::default()
+        };
+
+        // Ports are in network byte order, and only bits set in the mask are matched
+        spec.h_u[pdst..pdst + 2].copy_from_slice(&self.dst_port.to_be_bytes());
+        spec.m_u[pdst..pdst + 2].copy_from_slice(&[0xff, 0xff]);
+
+        spec
+    }
+}
+
+impl<'a> FlowRuleGuard<'a> {
+    /// Returns where the driver placed the rule
+    pub fn location(&self) -> u32 {
+        self.location
+    }
+}
+
+impl<'a> Drop for FlowRuleGuard<'a> {
+    fn drop(&mut self) {
+        let _ = self.ethtool.remove_flow_rule(self.location);
+    }
+}
+
+impl EthtoolRxnfc {
+    fn new(cmd: u32) -> Self {
+        EthtoolRxnfc {
+            cmd,
+            flow_type: 0,
+            data: 0,
+            fs: Default::default(),
+            rule_cnt: 0,
+        }
+    }
+}
+
+impl Default for EthtoolRxFlowSpec {
+    fn default() -> Self {
+        EthtoolRxFlowSpec {
+            flow_type: 0,
+            h_u: [0; 52],
+            h_ext: [0; 20],
+            m_u: [0; 52],
+            m_ext: [0; 20],
+            ring_cookie: 0,
+            location: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ethtool::{
+        EthtoolRxFlowSpec, EthtoolRxnfc, FlowProto, FlowRule, IfReq, RX_CLS_LOC_ANY, UDP_V6_FLOW,
+    };
+    use std::mem::offset_of;
+
+    #[test]
+    fn layout() {
+        assert_eq!(size_of::<EthtoolRxFlowSpec>(), 168);
+        assert_eq!(offset_of!(EthtoolRxFlowSpec, m_u), 76);
+        assert_eq!(offset_of!(EthtoolRxFlowSpec, ring_cookie), 152);
+        assert_eq!(offset_of!(EthtoolRxFlowSpec, location), 160);
+
+        assert_eq!(size_of::<EthtoolRxnfc>(), 192);
+        assert_eq!(offset_of!(EthtoolRxnfc, fs), 16);
+        assert_eq!(offset_of!(EthtoolRxnfc, rule_cnt), 184);
+
+        assert_eq!(size_of::<IfReq>(), 40);
+    }
+
+    #[test]
+    fn flow_spec() {
+        let spec = FlowRule::new(FlowProto::Udp6, 4791, 3).spec();
+
+        assert_eq!(spec.flow_type, UDP_V6_FLOW);
+        assert_eq!(spec.ring_cookie, 3);
+        assert_eq!(spec.location, RX_CLS_LOC_ANY);
+        assert_eq!(spec.h_u[34..36], 4791u16.to_be_bytes());
+        assert_eq!(spec.m_u[34..36], [0xff, 0xff]);
+        assert!(spec.m_u[..34].iter().all(|b| *b == 0));
+
+        let spec = FlowRule::new(FlowProto::Tcp4, 80, 1)
+            .with_location(7)
+            .spec();
+
+        assert_eq!(spec.location, 7);
+        assert_eq!(spec.h_u[10..12], 80u16.to_be_bytes());
+    }
+}
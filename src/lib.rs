@@ -1,32 +1,313 @@
+use libbpf_rs::MapType;
 use std::fmt::{Display, Formatter};
+use std::io;
+use std::num::TryFromIntError;
+use std::os::fd::RawFd;
+use std::path::PathBuf;
 
 pub(crate) mod assert;
+pub mod ethtool;
+pub mod metrics;
+pub mod netlink;
+pub mod perfbuf;
 pub mod ringbuf;
+pub mod topk;
 pub mod umem;
+pub mod user_ringbuf;
 pub mod utility;
 
+/// Errors returned by this crate. Variants for failed system calls carry the `OsError` that was
+/// captured right after the call, together with the parameters of the failing operation.
 #[derive(Debug)]
-enum Error {
-    InterfaceInvalid,
+#[non_exhaustive]
+pub enum Error {
+    /// `if_nametoindex` found no interface with the given name
+    InterfaceInvalid { name: String, os: OsError },
+    /// A name passed to the kernel contains a nul byte or is too long
+    InvalidName(String),
+    /// A size or address does not fit into the type the kernel expects
     Overflow,
+    /// The umem area is a null pointer
     InvalidUmem,
+    /// The umem area is not aligned to the page size
     UnalignedUmem,
-    PageSizeInvalid,
-    SocketFdInvalid,
-    Allocate,
-    UmemReg,
-    UmemRegFillRing,
-    WrongMapType,
-    ConsumerMmap,
-    ProducerMmap,
+    /// `sysconf(_SC_PAGE_SIZE)` failed
+    PageSizeInvalid { os: OsError },
+    /// Creating the `AF_XDP` socket failed
+    SocketFdInvalid { os: OsError },
+    /// Allocating the umem area failed
+    Allocate { size: usize, align: usize },
+    /// `setsockopt(XDP_UMEM_REG)` rejected the umem area
+    UmemReg {
+        address: u64,
+        length: u64,
+        chunk_size: u64,
+        headroom: u32,
+        flags: u32,
+        os: OsError,
+    },
+    /// `setsockopt(XDP_UMEM_FILL_RING)` rejected the fill ring size
+    UmemRegFillRing { size: u32, os: OsError },
+    /// The map has a different type than expected
+    WrongMapType { expected: MapType, found: MapType },
+    /// Mapping the consumer page of a ring buffer failed
+    ConsumerMmap { fd: RawFd, os: OsError },
+    /// Mapping the producer page and data area of a ring buffer failed
+    ProducerMmap { fd: RawFd, size: usize, os: OsError },
+    /// A record has a different size than the type it is decoded into
+    RecordSize { expected: usize, found: usize },
+    /// A record has the right size, but its contents are invalid
+    InvalidRecord(String),
+    /// A sample can never fit into the user ring buffer
+    SampleSize { size: usize, capacity: usize },
+    /// `epoll_create1` failed
+    EpollCreate { os: OsError },
+    /// `epoll_ctl(EPOLL_CTL_ADD)` failed
+    EpollCtl { fd: RawFd, os: OsError },
+    /// The user ring buffer has not enough space left for the sample
+    UserRingbufFull { size: usize, available: usize },
+    /// `perf_event_open` failed
+    PerfEventOpen { cpu: usize, os: OsError },
+    /// Mapping the perf buffer of a CPU failed
+    PerfEventMmap {
+        cpu: usize,
+        size: usize,
+        os: OsError,
+    },
+    /// `ioctl(PERF_EVENT_IOC_ENABLE)` failed
+    PerfEventEnable { cpu: usize, os: OsError },
+    /// The number of pages of a perf buffer is not a power of two
+    PerfBufferSize { pages: usize },
+    /// `bpf_obj_get` couldn't open the pinned map
+    PinnedMap { path: PathBuf, os: OsError },
+    /// The ring buffer size is not a power of two
+    RingbufSize { size: u32 },
+    /// The list of online CPUs couldn't be parsed
+    CpuList(String),
+    /// Creating the socket for ethtool commands failed
+    EthtoolSocket { os: OsError },
+    /// An ethtool ioctl failed, e.g. because the driver doesn't support the command
+    Ethtool {
+        interface: String,
+        command: u32,
+        os: OsError,
+    },
+    /// Opening or binding the netlink socket failed
+    NetlinkSocket { os: OsError },
+    /// Sending a netlink request failed
+    NetlinkSend { os: OsError },
+    /// Receiving a netlink reply failed
+    NetlinkRecv { os: OsError },
+    /// The kernel rejected a netlink request of the given message type
+    NetlinkRequest { request: u16, errno: i32 },
+    /// A netlink reply is truncated or lacks expected attributes
+    NetlinkMessage,
+    /// An I/O error, e.g. while waiting for readiness
+    Io(io::Error),
+    /// An error returned by libbpf
+    Bpf(libbpf_rs::Error),
+    /// An error returned by the metrics registry
+    Metrics(prometheus::Error),
+}
+
+impl Error {
+    /// Returns the failed system call, if the error was caused by one
+    pub fn os(&self) -> Option<&OsError> {
+        match self {
+            Error::InterfaceInvalid { os, .. }
+            | Error::PageSizeInvalid { os }
+            | Error::SocketFdInvalid { os }
+            | Error::UmemReg { os, .. }
+            | Error::UmemRegFillRing { os, .. }
+            | Error::ConsumerMmap { os, .. }
+            | Error::ProducerMmap { os, .. }
+            | Error::EpollCreate { os }
+            | Error::EpollCtl { os, .. }
+            | Error::PerfEventOpen { os, .. }
+            | Error::PerfEventMmap { os, .. }
+            | Error::PerfEventEnable { os, .. }
+            | Error::PinnedMap { os, .. }
+            | Error::EthtoolSocket { os }
+            | Error::Ethtool { os, .. }
+            | Error::NetlinkSocket { os }
+            | Error::NetlinkSend { os }
+            | Error::NetlinkRecv { os } => Some(os),
+            _ => None,
+        }
+    }
+
+    /// Returns the `errno` of a failed system call
+    pub fn errno(&self) -> Option<i32> {
+        match self {
+            Error::Io(err) => err.raw_os_error(),
+            Error::NetlinkRequest { errno, .. } => Some(*errno),
+            err => err.os().map(|os| os.errno),
+        }
+    }
+}
+
+/// A system call that failed, together with its `errno` and where it was made
+#[derive(Debug, Clone)]
+pub struct OsError {
+    /// The `errno` right after the call returned
+    pub errno: i32,
+    /// The call expression as written in the source
+    pub call: &'static str,
+    /// The source file the call was made in
+    pub file: &'static str,
+    /// The line of the call within `file`
+    pub line: u32,
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        todo!()
+        match self {
+            Error::InterfaceInvalid { name, .. } => write!(f, "no interface named {:?}", name)?,
+            Error::InvalidName(name) => write!(f, "invalid name {:?}", name)?,
+            Error::Overflow => write!(f, "value does not fit into the expected type")?,
+            Error::InvalidUmem => write!(f, "umem area is a null pointer")?,
+            Error::UnalignedUmem => write!(f, "umem area is not page aligned")?,
+            Error::PageSizeInvalid { .. } => write!(f, "can't get page size")?,
+            Error::SocketFdInvalid { .. } => write!(f, "can't create AF_XDP socket")?,
+            Error::Allocate { size, align } => write!(
+                f,
+                "can't allocate {} bytes aligned to {} bytes",
+                size, align
+            )?,
+            Error::UmemReg {
+                address,
+                length,
+                chunk_size,
+                headroom,
+                flags,
+                ..
+            } => write!(
+                f,
+                "setsockopt XDP_UMEM_REG failed for address {:#x}, length {}, chunk size {}, \
+                 headroom {}, flags {:#x}",
+                address, length, chunk_size, headroom, flags
+            )?,
+            Error::UmemRegFillRing { size, .. } => {
+                write!(f, "setsockopt XDP_UMEM_FILL_RING failed for size {}", size)?
+            }
+            Error::WrongMapType { expected, found } => {
+                write!(f, "expected map of type {:?}, found {:?}", expected, found)?
+            }
+            Error::ConsumerMmap { fd, .. } => {
+                write!(f, "can't map consumer page of map fd {}", fd)?
+            }
+            Error::ProducerMmap { fd, size, .. } => write!(
+                f,
+                "can't map {} bytes of producer page and data of map fd {}",
+                size, fd
+            )?,
+            Error::RecordSize { expected, found } => {
+                write!(f, "expected record of {} bytes, found {}", expected, found)?
+            }
+            Error::InvalidRecord(reason) => write!(f, "invalid record: {}", reason)?,
+            Error::SampleSize { size, capacity } => write!(
+                f,
+                "sample of {} bytes does not fit into ring buffer of {} bytes",
+                size, capacity
+            )?,
+            Error::EpollCreate { .. } => write!(f, "can't create epoll instance")?,
+            Error::EpollCtl { fd, .. } => write!(f, "can't add fd {} to epoll instance", fd)?,
+            Error::UserRingbufFull { size, available } => write!(
+                f,
+                "user ring buffer full, {} bytes needed, {} available",
+                size, available
+            )?,
+            Error::PerfEventOpen { cpu, .. } => write!(f, "can't open perf event on cpu {}", cpu)?,
+            Error::PerfEventMmap { cpu, size, .. } => {
+                write!(f, "can't map {} bytes of perf buffer on cpu {}", size, cpu)?
+            }
+            Error::PerfEventEnable { cpu, .. } => {
+                write!(f, "can't enable perf event on cpu {}", cpu)?
+            }
+            Error::PerfBufferSize { pages } => {
+                write!(f, "perf buffer pages must be a power of two, got {}", pages)?
+            }
+            Error::PinnedMap { path, .. } => write!(f, "can't open pinned map {}", path.display())?,
+            Error::RingbufSize { size } => {
+                write!(f, "ring buffer size must be a power of two, got {}", size)?
+            }
+            Error::CpuList(list) => write!(f, "can't parse cpu list {:?}", list)?,
+            Error::EthtoolSocket { .. } => write!(f, "can't create ethtool socket")?,
+            Error::Ethtool {
+                interface, command, ..
+            } => write!(f, "ethtool command {:#x} failed on {}", command, interface)?,
+            Error::NetlinkSocket { .. } => write!(f, "can't open netlink socket")?,
+            Error::NetlinkSend { .. } => write!(f, "can't send netlink request")?,
+            Error::NetlinkRecv { .. } => write!(f, "can't receive netlink reply")?,
+            Error::NetlinkRequest { request, errno } => {
+                return write!(
+                    f,
+                    "netlink request of type {} failed: {}",
+                    request,
+                    io::Error::from_raw_os_error(*errno)
+                )
+            }
+            Error::NetlinkMessage => write!(f, "malformed netlink reply")?,
+            Error::Io(err) => return write!(f, "{}", err),
+            Error::Bpf(err) => return write!(f, "{}", err),
+            Error::Metrics(err) => return write!(f, "{}", err),
+        }
+
+        match self.os() {
+            Some(os) => write!(f, ": {}", os),
+            None => Ok(()),
+        }
     }
 }
 
-impl std::error::Error for Error {}
+impl Display for OsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "`{}` failed at {}:{}: {}",
+            self.call,
+            self.file,
+            self.line,
+            io::Error::from_raw_os_error(self.errno)
+        )
+    }
+}
+
+impl std::error::Error for OsError {}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Bpf(err) => Some(err),
+            Error::Metrics(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<libbpf_rs::Error> for Error {
+    fn from(err: libbpf_rs::Error) -> Self {
+        Error::Bpf(err)
+    }
+}
+
+impl From<prometheus::Error> for Error {
+    fn from(err: prometheus::Error) -> Self {
+        Error::Metrics(err)
+    }
+}
+
+impl From<TryFromIntError> for Error {
+    fn from(_: TryFromIntError) -> Self {
+        Error::Overflow
+    }
+}
 
-type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+pub type Result<T> = std::result::Result<T, Error>;
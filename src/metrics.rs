@@ -0,0 +1,81 @@
+use crate::ringbuf::RingbufStats;
+use crate::Result;
+use prometheus::{Gauge, IntGauge, Opts, Registry};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Periodically turns the fill level of a ring buffer into gauges
+#[derive(Debug, Clone)]
+pub struct RingbufSampler {
+    stats: RingbufStats,
+    capacity: IntGauge,
+    pending: IntGauge,
+    producer: IntGauge,
+    consumer: IntGauge,
+    fill: Gauge,
+}
+
+impl RingbufSampler {
+    /// Creates gauges for the given ring buffer, labeled with `name`
+    pub fn new(stats: RingbufStats, name: &str) -> Result<Self> {
+        let opts = |metric: &str, help: &str| {
+            Opts::new(metric, help)
+                .namespace("ringbuf")
+                .const_label("ring", name)
+        };
+
+        Ok(RingbufSampler {
+            capacity: IntGauge::with_opts(opts("capacity_bytes", "Size of the data area"))?,
+            pending: IntGauge::with_opts(opts("pending_bytes", "Bytes not consumed yet"))?,
+            producer: IntGauge::with_opts(opts("producer_pos", "Producer position"))?,
+            consumer: IntGauge::with_opts(opts("consumer_pos", "Consumer position"))?,
+            fill: Gauge::with_opts(opts("fill_ratio", "Pending bytes relative to the capacity"))?,
+            stats,
+        })
+    }
+
+    /// Registers all gauges with the given registry
+    pub fn register(&self, registry: &Registry) -> Result<()> {
+        registry.register(Box::new(self.capacity.clone()))?;
+        registry.register(Box::new(self.pending.clone()))?;
+        registry.register(Box::new(self.producer.clone()))?;
+        registry.register(Box::new(self.consumer.clone()))?;
+        registry.register(Box::new(self.fill.clone()))?;
+
+        Ok(())
+    }
+
+    /// Updates all gauges from the current positions
+    pub fn sample(&self) {
+        let capacity = self.stats.capacity();
+        let pending = self.stats.pending();
+
+        self.capacity.set(capacity as i64);
+        self.pending.set(pending as i64);
+        self.producer.set(self.stats.producer_pos() as i64);
+        self.consumer.set(self.stats.consumer_pos() as i64);
+        self.fill.set(pending as f64 / capacity as f64);
+    }
+
+    /// Samples the ring buffer every `interval` until the returned handle is dropped
+    pub fn spawn(self, interval: Duration) -> SamplerHandle {
+        SamplerHandle(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+
+            loop {
+                interval.tick().await;
+                self.sample();
+            }
+        }))
+    }
+}
+
+/// A running sampler task, which is aborted once the handle is dropped
+#[derive(Debug)]
+pub struct SamplerHandle(JoinHandle<()>);
+
+impl Drop for SamplerHandle {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
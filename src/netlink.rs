@@ -0,0 +1,953 @@
+use crate::assert::{unsafe_no_panic, ExpectDefault, ExpectNonNegative};
+use crate::{Error, Result};
+use libc::{
+    bind, c_int, nlmsghdr, recv, send, sockaddr, sockaddr_nl, socket, socklen_t, AF_BRIDGE,
+    AF_NETLINK, AF_UNSPEC, ENOBUFS, IFLA_ADDRESS, IFLA_EXT_MASK, IFLA_IFNAME, IFLA_MTU,
+    IFLA_NUM_RX_QUEUES, IFLA_NUM_TX_QUEUES, IFLA_OPERSTATE, IFLA_XDP, IF_OPER_DORMANT,
+    IF_OPER_DOWN, IF_OPER_LOWERLAYERDOWN, IF_OPER_NOTPRESENT, IF_OPER_TESTING, IF_OPER_UP,
+    NETLINK_ROUTE, NLA_F_NESTED, NLA_TYPE_MASK, NLMSG_DONE, NLMSG_ERROR, NLM_F_ACK, NLM_F_DUMP,
+    NLM_F_REQUEST, RTEXT_FILTER_SKIP_STATS, RTMGRP_LINK, RTM_DELLINK, RTM_GETLINK, RTM_NEWLINK,
+    RTM_SETLINK, SOCK_CLOEXEC, SOCK_NONBLOCK, SOCK_RAW,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::CStr;
+use std::io;
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+
+const NETLINK_BUFFER_SIZE: usize = 64 * 1024;
+
+const NLMSG_HDRLEN: usize = size_of::<nlmsghdr>();
+const NLA_HDRLEN: usize = 4;
+const IFINFOMSG_LEN: usize = 16;
+
+const IFLA_XDP_FD: u16 = 1;
+const IFLA_XDP_ATTACHED: u16 = 2;
+const IFLA_XDP_FLAGS: u16 = 3;
+const IFLA_XDP_PROG_ID: u16 = 4;
+const IFLA_XDP_DRV_PROG_ID: u16 = 5;
+const IFLA_XDP_SKB_PROG_ID: u16 = 6;
+const IFLA_XDP_HW_PROG_ID: u16 = 7;
+const IFLA_XDP_EXPECTED_FD: u16 = 8;
+
+const XDP_FLAGS_UPDATE_IF_NOEXIST: u32 = 1 << 0;
+const XDP_FLAGS_SKB_MODE: u32 = 1 << 1;
+const XDP_FLAGS_DRV_MODE: u32 = 1 << 2;
+const XDP_FLAGS_HW_MODE: u32 = 1 << 3;
+const XDP_FLAGS_REPLACE: u32 = 1 << 4;
+
+const XDP_ATTACHED_DRV: u8 = 1;
+const XDP_ATTACHED_SKB: u8 = 2;
+const XDP_ATTACHED_HW: u8 = 3;
+const XDP_ATTACHED_MULTI: u8 = 4;
+
+/// A route netlink socket to query and configure network interfaces
+#[derive(Debug)]
+pub struct Netlink {
+    fd: OwnedFd,
+    seq: u32,
+    buf: Vec<u8>,
+}
+
+/// An interface as reported by `RTM_GETLINK`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkInfo {
+    index: u32,
+    flags: u32,
+    name: String,
+    mtu: u32,
+    mac: Vec<u8>,
+    operstate: OperState,
+    rx_queues: u32,
+    tx_queues: u32,
+    xdp_attached: XdpAttached,
+    xdp_prog_id: Option<u32>,
+    xdp_programs: XdpPrograms,
+}
+
+/// Operational state of an interface, as defined in RFC 2863
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperState {
+    Unknown,
+    NotPresent,
+    Down,
+    LowerLayerDown,
+    Testing,
+    Dormant,
+    Up,
+}
+
+/// How XDP programs are attached to an interface
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XdpAttached {
+    None,
+    Driver,
+    Skb,
+    Hardware,
+    /// Programs are attached in more than one mode
+    Multi,
+}
+
+/// Mode an XDP program is attached in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XdpMode {
+    /// Generic XDP, run on socket buffers. Works with every driver.
+    Skb,
+    /// Native XDP, run by the driver before socket buffers are allocated
+    Driver,
+    /// Offloaded to the NIC
+    Hardware,
+}
+
+/// Ids of the XDP programs attached to an interface, per mode
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct XdpPrograms {
+    skb: Option<u32>,
+    driver: Option<u32>,
+    hardware: Option<u32>,
+}
+
+/// How an XDP program is attached or detached through `IFLA_XDP`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct XdpOptions<'a> {
+    mode: Option<XdpMode>,
+    if_noexist: bool,
+    expected: Option<BorrowedFd<'a>>,
+}
+
+/// Watches interfaces as they come and go, through `RTM_NEWLINK` and `RTM_DELLINK`
+/// notifications
+#[derive(Debug)]
+pub struct LinkWatcher {
+    netlink: AsyncFd<Netlink>,
+    links: HashMap<u32, LinkInfo>,
+    events: VecDeque<LinkEvent>,
+}
+
+/// A change of an interface
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkEvent {
+    /// The interface appeared or one of its attributes changed
+    New(LinkInfo),
+    /// The interface went away
+    Del(LinkInfo),
+}
+
+/// A netlink request, made of a header, a fixed payload and attributes
+pub(crate) struct Message {
+    buf: Vec<u8>,
+}
+
+/// Iterates over the attributes of a netlink message
+pub(crate) struct Attributes<'a> {
+    buf: &'a [u8],
+}
+
+impl Netlink {
+    /// Opens a route netlink socket
+    pub fn new() -> Result<Self> {
+        Self::open(0, 0)
+    }
+
+    /// Opens a route netlink socket that is subscribed to the given multicast groups. `flags` are
+    /// added to the socket type, e.g. `SOCK_NONBLOCK`.
+    fn open(groups: u32, flags: c_int) -> Result<Self> {
+        let fd = unsafe_no_panic!(socket(
+            AF_NETLINK,
+            SOCK_RAW | SOCK_CLOEXEC | flags,
+            NETLINK_ROUTE
+        ))
+        .expect(ExpectNonNegative, |os| Error::NetlinkSocket { os })?;
+
+        // SAFETY: File Descriptor was properly checked
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // SAFETY: sockaddr_nl is plain data, all zeroes lets the kernel pick our port id
+        let mut addr: sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = AF_NETLINK as _;
+        addr.nl_groups = groups;
+
+        unsafe_no_panic!(bind(
+            fd.as_raw_fd(),
+            &addr as *const sockaddr_nl as *const sockaddr,
+            size_of::<sockaddr_nl>() as socklen_t,
+        ))
+        .expect(ExpectDefault, |os| Error::NetlinkSocket { os })?;
+
+        Ok(Netlink {
+            fd,
+            seq: 0,
+            buf: vec![0; NETLINK_BUFFER_SIZE],
+        })
+    }
+
+    /// Returns all interfaces
+    pub fn links(&mut self) -> Result<Vec<LinkInfo>> {
+        let mut message = Message::new(RTM_GETLINK, NLM_F_DUMP);
+        message.push(&ifinfomsg(0));
+        message.attr(
+            IFLA_EXT_MASK,
+            &(RTEXT_FILTER_SKIP_STATS as u32).to_ne_bytes(),
+        );
+
+        let mut links = vec![];
+        self.request(message, |kind, payload| {
+            if kind == RTM_NEWLINK {
+                links.push(LinkInfo::parse(payload)?);
+            }
+
+            Ok(())
+        })?;
+
+        Ok(links)
+    }
+
+    /// Returns the interface with the given index
+    pub fn link(&mut self, index: u32) -> Result<LinkInfo> {
+        let mut message = Message::new(RTM_GETLINK, 0);
+        message.push(&ifinfomsg(index as i32));
+        message.attr(
+            IFLA_EXT_MASK,
+            &(RTEXT_FILTER_SKIP_STATS as u32).to_ne_bytes(),
+        );
+
+        self.request_link(message)
+    }
+
+    /// Returns the interface with the given name
+    pub fn link_by_name(&mut self, name: &str) -> Result<LinkInfo> {
+        let mut message = Message::new(RTM_GETLINK, 0);
+        message.push(&ifinfomsg(0));
+        message.attr(
+            IFLA_EXT_MASK,
+            &(RTEXT_FILTER_SKIP_STATS as u32).to_ne_bytes(),
+        );
+        message.attr_str(IFLA_IFNAME, name)?;
+
+        self.request_link(message)
+    }
+
+    /// Attaches an XDP program to the interface with the given index
+    pub fn attach_xdp(&mut self, index: u32, prog: BorrowedFd, options: XdpOptions) -> Result<()> {
+        self.set_xdp(index, prog.as_raw_fd(), options)
+    }
+
+    /// Detaches the XDP program of the given mode from the interface with the given index
+    pub fn detach_xdp(&mut self, index: u32, options: XdpOptions) -> Result<()> {
+        self.set_xdp(index, -1, options)
+    }
+
+    /// Returns the ids of the XDP programs attached to the interface with the given index
+    pub fn xdp_programs(&mut self, index: u32) -> Result<XdpPrograms> {
+        Ok(self.link(index)?.xdp_programs())
+    }
+
+    fn set_xdp(&mut self, index: u32, fd: i32, options: XdpOptions) -> Result<()> {
+        let mut message = Message::new(RTM_SETLINK, 0);
+        message.push(&ifinfomsg(index.try_into()?));
+        message.nested(IFLA_XDP, |xdp| {
+            xdp.attr(IFLA_XDP_FD, &fd.to_ne_bytes());
+            xdp.attr(IFLA_XDP_FLAGS, &options.flags().to_ne_bytes());
+
+            if let Some(expected) = options.expected {
+                xdp.attr(IFLA_XDP_EXPECTED_FD, &expected.as_raw_fd().to_ne_bytes());
+            }
+        });
+
+        self.request(message, |_, _| Ok(()))
+    }
+
+    fn request_link(&mut self, message: Message) -> Result<LinkInfo> {
+        let mut link = None;
+
+        self.request(message, |kind, payload| {
+            if kind == RTM_NEWLINK {
+                link = Some(LinkInfo::parse(payload)?);
+            }
+
+            Ok(())
+        })?;
+
+        link.ok_or(Error::NetlinkMessage)
+    }
+
+    /// Sends a request and hands every reply to `f`, until the kernel signals the end of a dump,
+    /// acknowledges the request or returns an error
+    pub(crate) fn request<F>(&mut self, message: Message, mut f: F) -> Result<()>
+    where
+        F: FnMut(u16, &[u8]) -> Result<()>,
+    {
+        self.seq = self.seq.wrapping_add(1);
+        let kind = message.kind();
+        let request = message.finish(self.seq);
+
+        unsafe_no_panic!(send(
+            self.fd.as_raw_fd(),
+            request.as_ptr() as _,
+            request.len(),
+            0
+        ))
+        .expect(ExpectNonNegative, |os| Error::NetlinkSend { os })?;
+
+        loop {
+            let len = self.recv()?;
+            let mut buf = &self.buf[..len];
+
+            while let Some((header, payload, rest)) = split_message(buf)? {
+                buf = rest;
+
+                // Replies to other requests, e.g. an earlier one that failed halfway
+                if header.nlmsg_seq != self.seq {
+                    continue;
+                }
+
+                match header.nlmsg_type as c_int {
+                    NLMSG_DONE => return Ok(()),
+                    NLMSG_ERROR => {
+                        let error = payload
+                            .get(..4)
+                            .ok_or(Error::NetlinkMessage)?
+                            .try_into()
+                            .map(i32::from_ne_bytes)
+                            .map_err(|_| Error::NetlinkMessage)?;
+
+                        return match error {
+                            0 => Ok(()),
+                            error => Err(Error::NetlinkRequest {
+                                request: kind,
+                                errno: -error,
+                            }),
+                        };
+                    }
+                    _ => f(header.nlmsg_type, payload)?,
+                }
+            }
+        }
+    }
+
+    /// Receives the next datagram into the buffer and returns its length
+    pub(crate) fn recv(&mut self) -> Result<usize> {
+        let len = unsafe_no_panic!(recv(
+            self.fd.as_raw_fd(),
+            self.buf.as_mut_ptr() as _,
+            self.buf.len(),
+            0
+        ))
+        .expect(ExpectNonNegative, |os| Error::NetlinkRecv { os })?;
+
+        Ok(len as usize)
+    }
+
+    /// Like `recv`, for non-blocking sockets that are polled for readiness
+    fn try_recv(&mut self) -> io::Result<usize> {
+        let len = unsafe {
+            recv(
+                self.fd.as_raw_fd(),
+                self.buf.as_mut_ptr() as _,
+                self.buf.len(),
+                0,
+            )
+        };
+
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(len as usize)
+    }
+}
+
+impl LinkWatcher {
+    /// Subscribes to link notifications. Interfaces that already exist are reported first.
+    pub fn new() -> Result<Self> {
+        let netlink = Netlink::open(RTMGRP_LINK as u32, SOCK_NONBLOCK)?;
+
+        let mut watcher = LinkWatcher {
+            netlink: AsyncFd::with_interest(netlink, Interest::READABLE)?,
+            links: HashMap::new(),
+            events: VecDeque::new(),
+        };
+
+        watcher.resync()?;
+
+        Ok(watcher)
+    }
+
+    /// Waits for the next interface to appear, change or go away
+    pub async fn next(&mut self) -> Result<LinkEvent> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(event);
+            }
+
+            let mut guard = self.netlink.readable_mut().await?;
+            let received = match guard.try_io(|netlink| netlink.get_mut().try_recv()) {
+                Ok(received) => received,
+                Err(_) => continue,
+            };
+
+            match received {
+                Ok(len) => self.dispatch(len)?,
+                // The socket buffer overflowed and notifications were lost, so we start over
+                Err(err) if err.raw_os_error() == Some(ENOBUFS) => self.resync()?,
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    /// Turns the notifications of a received datagram into events
+    fn dispatch(&mut self, len: usize) -> Result<()> {
+        let buf = &self.netlink.get_ref().buf[..len];
+
+        link_events(buf, &mut self.links, &mut self.events)
+    }
+
+    /// Lists all interfaces and reports those that went away since the last listing as removed,
+    /// and all others as new
+    fn resync(&mut self) -> Result<()> {
+        let links = Netlink::new()?.links()?;
+        let present: HashSet<u32> = links.iter().map(LinkInfo::index).collect();
+
+        let events = &mut self.events;
+        self.links.retain(|index, link| {
+            let keep = present.contains(index);
+
+            if !keep {
+                events.push_back(LinkEvent::Del(link.clone()));
+            }
+
+            keep
+        });
+
+        for link in links {
+            self.links.insert(link.index(), link.clone());
+            self.events.push_back(LinkEvent::New(link));
+        }
+
+        Ok(())
+    }
+}
+
+impl AsRawFd for Netlink {
+    fn as_raw_fd(&self) -> c_int {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl LinkInfo {
+    /// Parses the payload of a `RTM_NEWLINK` message
+    pub(crate) fn parse(payload: &[u8]) -> Result<Self> {
+        let header = payload.get(..IFINFOMSG_LEN).ok_or(Error::NetlinkMessage)?;
+
+        let mut link = LinkInfo {
+            index: u32::from_ne_bytes(header[4..8].try_into().unwrap()),
+            flags: u32::from_ne_bytes(header[8..12].try_into().unwrap()),
+            name: String::new(),
+            mtu: 0,
+            mac: vec![],
+            operstate: OperState::Unknown,
+            rx_queues: 0,
+            tx_queues: 0,
+            xdp_attached: XdpAttached::None,
+            xdp_prog_id: None,
+            xdp_programs: XdpPrograms::default(),
+        };
+
+        for (kind, data) in Attributes::new(&payload[IFINFOMSG_LEN..]) {
+            match kind {
+                IFLA_IFNAME => link.name = attr_str(data)?,
+                IFLA_MTU => link.mtu = attr_u32(data)?,
+                IFLA_ADDRESS => link.mac = data.to_vec(),
+                IFLA_OPERSTATE => link.operstate = OperState::from(attr_u8(data)?),
+                IFLA_NUM_RX_QUEUES => link.rx_queues = attr_u32(data)?,
+                IFLA_NUM_TX_QUEUES => link.tx_queues = attr_u32(data)?,
+                IFLA_XDP => {
+                    for (kind, data) in Attributes::new(data) {
+                        match kind {
+                            IFLA_XDP_ATTACHED => {
+                                link.xdp_attached = XdpAttached::from(attr_u8(data)?)
+                            }
+                            IFLA_XDP_PROG_ID => link.xdp_prog_id = Some(attr_u32(data)?),
+                            IFLA_XDP_SKB_PROG_ID => link.xdp_programs.skb = Some(attr_u32(data)?),
+                            IFLA_XDP_DRV_PROG_ID => {
+                                link.xdp_programs.driver = Some(attr_u32(data)?)
+                            }
+                            IFLA_XDP_HW_PROG_ID => {
+                                link.xdp_programs.hardware = Some(attr_u32(data)?)
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(link)
+    }
+
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    /// Returns the device flags, like `IFF_UP`
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn mtu(&self) -> u32 {
+        self.mtu
+    }
+
+    /// Returns the link layer address, if the interface has one
+    pub fn mac(&self) -> Option<&[u8]> {
+        match self.mac.is_empty() {
+            true => None,
+            false => Some(&self.mac),
+        }
+    }
+
+    pub fn operstate(&self) -> OperState {
+        self.operstate
+    }
+
+    pub fn rx_queues(&self) -> u32 {
+        self.rx_queues
+    }
+
+    pub fn tx_queues(&self) -> u32 {
+        self.tx_queues
+    }
+
+    pub fn xdp_attached(&self) -> XdpAttached {
+        self.xdp_attached
+    }
+
+    /// Returns the id of the attached XDP program. This is not reported if programs are
+    /// attached in more than one mode.
+    pub fn xdp_prog_id(&self) -> Option<u32> {
+        self.xdp_prog_id
+    }
+
+    /// Returns the ids of the attached XDP programs per mode, which are reported even if
+    /// programs are attached in more than one mode
+    pub fn xdp_programs(&self) -> XdpPrograms {
+        self.xdp_programs
+    }
+}
+
+impl XdpPrograms {
+    /// Returns the id of the program attached in the given mode
+    pub fn get(&self, mode: XdpMode) -> Option<u32> {
+        match mode {
+            XdpMode::Skb => self.skb,
+            XdpMode::Driver => self.driver,
+            XdpMode::Hardware => self.hardware,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.skb.is_none() && self.driver.is_none() && self.hardware.is_none()
+    }
+}
+
+impl<'a> XdpOptions<'a> {
+    /// Lets the kernel pick the mode, which is driver mode if supported and SKB mode otherwise.
+    /// Replaces any program that is attached in that mode.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Attaches in the given mode only
+    pub fn mode(mut self, mode: XdpMode) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Fails with `EBUSY` instead of replacing a program that is already attached
+    pub fn if_noexist(mut self) -> Self {
+        self.if_noexist = true;
+        self
+    }
+
+    /// Only replaces or detaches the program if it's the one behind `expected`
+    pub fn replace(mut self, expected: BorrowedFd<'a>) -> Self {
+        self.expected = Some(expected);
+        self
+    }
+
+    fn flags(&self) -> u32 {
+        let mut flags = match self.mode {
+            None => 0,
+            Some(XdpMode::Skb) => XDP_FLAGS_SKB_MODE,
+            Some(XdpMode::Driver) => XDP_FLAGS_DRV_MODE,
+            Some(XdpMode::Hardware) => XDP_FLAGS_HW_MODE,
+        };
+
+        if self.if_noexist {
+            flags |= XDP_FLAGS_UPDATE_IF_NOEXIST;
+        }
+
+        if self.expected.is_some() {
+            flags |= XDP_FLAGS_REPLACE;
+        }
+
+        flags
+    }
+}
+
+impl From<u8> for OperState {
+    fn from(state: u8) -> Self {
+        match state as c_int {
+            IF_OPER_NOTPRESENT => OperState::NotPresent,
+            IF_OPER_DOWN => OperState::Down,
+            IF_OPER_LOWERLAYERDOWN => OperState::LowerLayerDown,
+            IF_OPER_TESTING => OperState::Testing,
+            IF_OPER_DORMANT => OperState::Dormant,
+            IF_OPER_UP => OperState::Up,
+            _ => OperState::Unknown,
+        }
+    }
+}
+
+impl From<u8> for XdpAttached {
+    fn from(attached: u8) -> Self {
+        match attached {
+            XDP_ATTACHED_DRV => XdpAttached::Driver,
+            XDP_ATTACHED_SKB => XdpAttached::Skb,
+            XDP_ATTACHED_HW => XdpAttached::Hardware,
+            XDP_ATTACHED_MULTI => XdpAttached::Multi,
+            _ => XdpAttached::None,
+        }
+    }
+}
+
+impl Message {
+    /// Starts a request of the given type. `NLM_F_REQUEST` and `NLM_F_ACK` are always set.
+    pub(crate) fn new(kind: u16, flags: c_int) -> Self {
+        let mut buf = vec![0; NLMSG_HDRLEN];
+        buf[4..6].copy_from_slice(&kind.to_ne_bytes());
+        buf[6..8].copy_from_slice(&((flags | NLM_F_REQUEST | NLM_F_ACK) as u16).to_ne_bytes());
+
+        Message { buf }
+    }
+
+    fn kind(&self) -> u16 {
+        u16::from_ne_bytes(self.buf[4..6].try_into().unwrap())
+    }
+
+    /// Appends the fixed payload of the request, e.g. an `ifinfomsg`
+    pub(crate) fn push(&mut self, payload: &[u8]) {
+        self.buf.extend_from_slice(payload);
+        self.align();
+    }
+
+    /// Appends an attribute
+    pub(crate) fn attr(&mut self, kind: u16, data: &[u8]) {
+        self.buf
+            .extend_from_slice(&((NLA_HDRLEN + data.len()) as u16).to_ne_bytes());
+        self.buf.extend_from_slice(&kind.to_ne_bytes());
+        self.push(data);
+    }
+
+    /// Appends a nul-terminated string attribute
+    pub(crate) fn attr_str(&mut self, kind: u16, data: &str) -> Result<()> {
+        if data.contains('\0') {
+            return Err(Error::InvalidName(data.to_string()));
+        }
+
+        let mut bytes = data.as_bytes().to_vec();
+        bytes.push(0);
+        self.attr(kind, &bytes);
+
+        Ok(())
+    }
+
+    /// Appends a nested attribute, whose attributes are added by `f`
+    pub(crate) fn nested<F>(&mut self, kind: u16, f: F)
+    where
+        F: FnOnce(&mut Message),
+    {
+        let start = self.buf.len();
+        self.attr(kind | NLA_F_NESTED as u16, &[]);
+        f(self);
+
+        let len = (self.buf.len() - start) as u16;
+        self.buf[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+    }
+
+    /// Sets length and sequence number and returns the raw request
+    pub(crate) fn finish(mut self, seq: u32) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf[8..12].copy_from_slice(&seq.to_ne_bytes());
+
+        self.buf
+    }
+
+    fn align(&mut self) {
+        self.buf.resize(align(self.buf.len()), 0);
+    }
+}
+
+impl<'a> Attributes<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> Self {
+        Attributes { buf }
+    }
+}
+
+impl<'a> Iterator for Attributes<'a> {
+    type Item = (u16, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let len = u16::from_ne_bytes(self.buf.get(0..2)?.try_into().unwrap()) as usize;
+        let kind = u16::from_ne_bytes(self.buf.get(2..4)?.try_into().unwrap());
+
+        // A truncated attribute ends the iteration, as nothing after it can be trusted
+        if len < NLA_HDRLEN || len > self.buf.len() {
+            return None;
+        }
+
+        let data = &self.buf[NLA_HDRLEN..len];
+        self.buf = &self.buf[align(len).min(self.buf.len())..];
+
+        Some((kind & NLA_TYPE_MASK as u16, data))
+    }
+}
+
+/// A message header, the message payload and the rest of the datagram
+type Split<'a> = (nlmsghdr, &'a [u8], &'a [u8]);
+
+/// Splits the next message off a datagram
+pub(crate) fn split_message(buf: &[u8]) -> Result<Option<Split<'_>>> {
+    if buf.len() < NLMSG_HDRLEN {
+        return Ok(None);
+    }
+
+    let field = |at: usize| u32::from_ne_bytes(buf[at..at + 4].try_into().unwrap());
+    let header = nlmsghdr {
+        nlmsg_len: field(0),
+        nlmsg_type: u16::from_ne_bytes(buf[4..6].try_into().unwrap()),
+        nlmsg_flags: u16::from_ne_bytes(buf[6..8].try_into().unwrap()),
+        nlmsg_seq: field(8),
+        nlmsg_pid: field(12),
+    };
+
+    let len = header.nlmsg_len as usize;
+    if len < NLMSG_HDRLEN || len > buf.len() {
+        return Err(Error::NetlinkMessage);
+    }
+
+    Ok(Some((
+        header,
+        &buf[NLMSG_HDRLEN..len],
+        &buf[align(len).min(buf.len())..],
+    )))
+}
+
+/// Turns link notifications into events and keeps track of the interfaces that exist
+fn link_events(
+    mut buf: &[u8],
+    links: &mut HashMap<u32, LinkInfo>,
+    events: &mut VecDeque<LinkEvent>,
+) -> Result<()> {
+    while let Some((header, payload, rest)) = split_message(buf)? {
+        buf = rest;
+
+        // Bridges report changes of their ports with AF_BRIDGE, e.g. a RTM_DELLINK once a port
+        // leaves the bridge, while the interface itself stays
+        if payload.first() == Some(&(AF_BRIDGE as u8)) {
+            continue;
+        }
+
+        match header.nlmsg_type {
+            RTM_NEWLINK => {
+                let link = LinkInfo::parse(payload)?;
+
+                links.insert(link.index(), link.clone());
+                events.push_back(LinkEvent::New(link));
+            }
+            RTM_DELLINK => {
+                let link = LinkInfo::parse(payload)?;
+
+                links.remove(&link.index());
+                events.push_back(LinkEvent::Del(link));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns an `ifinfomsg` selecting the interface with the given index, or all if it's zero
+pub(crate) fn ifinfomsg(index: i32) -> [u8; IFINFOMSG_LEN] {
+    let mut msg = [0; IFINFOMSG_LEN];
+    msg[0] = AF_UNSPEC as u8;
+    msg[4..8].copy_from_slice(&index.to_ne_bytes());
+
+    msg
+}
+
+fn attr_u8(data: &[u8]) -> Result<u8> {
+    data.first().copied().ok_or(Error::NetlinkMessage)
+}
+
+fn attr_u32(data: &[u8]) -> Result<u32> {
+    Ok(u32::from_ne_bytes(
+        data.get(..4)
+            .ok_or(Error::NetlinkMessage)?
+            .try_into()
+            .unwrap(),
+    ))
+}
+
+fn attr_str(data: &[u8]) -> Result<String> {
+    let name = CStr::from_bytes_until_nul(data).map_err(|_| Error::NetlinkMessage)?;
+
+    Ok(name.to_string_lossy().into_owned())
+}
+
+/// Aligns to the 4 byte boundary of netlink messages and attributes
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+#[cfg(test)]
+mod test {
+    use crate::netlink::{
+        ifinfomsg, link_events, split_message, Attributes, LinkEvent, LinkInfo, Message, OperState,
+        XdpAttached, XdpMode, XdpOptions, IFINFOMSG_LEN, IFLA_XDP_ATTACHED, IFLA_XDP_DRV_PROG_ID,
+        IFLA_XDP_FD, IFLA_XDP_PROG_ID, IFLA_XDP_SKB_PROG_ID, NLMSG_HDRLEN, XDP_FLAGS_REPLACE,
+        XDP_FLAGS_SKB_MODE, XDP_FLAGS_UPDATE_IF_NOEXIST,
+    };
+    use libc::AF_BRIDGE;
+    use libc::{
+        IFF_UP, IFLA_ADDRESS, IFLA_IFNAME, IFLA_MTU, IFLA_NUM_RX_QUEUES, IFLA_NUM_TX_QUEUES,
+        IFLA_OPERSTATE, IFLA_XDP, RTM_DELLINK, RTM_NEWLINK, RTM_SETLINK,
+    };
+    use std::collections::{HashMap, VecDeque};
+    use std::os::fd::AsFd;
+
+    #[test]
+    fn parse_link() {
+        let mut header = ifinfomsg(7);
+        header[8..12].copy_from_slice(&(IFF_UP as u32).to_ne_bytes());
+
+        let mut message = Message::new(RTM_NEWLINK, 0);
+        message.push(&header);
+        message.attr_str(IFLA_IFNAME, "eth0").unwrap();
+        message.attr(IFLA_MTU, &1500u32.to_ne_bytes());
+        message.attr(IFLA_ADDRESS, &[2, 0, 0, 0, 0, 1]);
+        message.attr(IFLA_OPERSTATE, &[6]);
+        message.attr(IFLA_NUM_RX_QUEUES, &4u32.to_ne_bytes());
+        message.attr(IFLA_NUM_TX_QUEUES, &8u32.to_ne_bytes());
+
+        // Nested attributes are laid out like the attributes of a message
+        let mut xdp = Message::new(0, 0);
+        xdp.attr(IFLA_XDP_ATTACHED, &[1]);
+        xdp.attr(IFLA_XDP_PROG_ID, &42u32.to_ne_bytes());
+        xdp.attr(IFLA_XDP_DRV_PROG_ID, &42u32.to_ne_bytes());
+        message.attr(IFLA_XDP, &xdp.finish(0)[NLMSG_HDRLEN..]);
+
+        let raw = message.finish(1);
+        let (header, payload, rest) = split_message(&raw).unwrap().unwrap();
+
+        assert_eq!(header.nlmsg_type, RTM_NEWLINK);
+        assert_eq!(header.nlmsg_seq, 1);
+        assert!(rest.is_empty());
+
+        let link = LinkInfo::parse(payload).unwrap();
+
+        assert_eq!(link.index(), 7);
+        assert_eq!(link.flags(), IFF_UP as u32);
+        assert_eq!(link.name(), "eth0");
+        assert_eq!(link.mtu(), 1500);
+        assert_eq!(link.mac(), Some(&[2, 0, 0, 0, 0, 1][..]));
+        assert_eq!(link.operstate(), OperState::Up);
+        assert_eq!(link.rx_queues(), 4);
+        assert_eq!(link.tx_queues(), 8);
+        assert_eq!(link.xdp_attached(), XdpAttached::Driver);
+        assert_eq!(link.xdp_prog_id(), Some(42));
+        assert_eq!(link.xdp_programs().get(XdpMode::Driver), Some(42));
+        assert_eq!(link.xdp_programs().get(XdpMode::Skb), None);
+    }
+
+    #[test]
+    fn nested_xdp_attributes() {
+        let mut message = Message::new(RTM_SETLINK, 0);
+        message.push(&ifinfomsg(3));
+        message.nested(IFLA_XDP, |xdp| {
+            xdp.attr(IFLA_XDP_FD, &5i32.to_ne_bytes());
+            xdp.attr(IFLA_XDP_SKB_PROG_ID, &9u32.to_ne_bytes());
+        });
+        message.attr(IFLA_MTU, &1500u32.to_ne_bytes());
+
+        let raw = message.finish(1);
+        let (_, payload, _) = split_message(&raw).unwrap().unwrap();
+        let mut attributes = Attributes::new(&payload[IFINFOMSG_LEN..]);
+
+        // The nested flag is masked off, and the attribute covers exactly its children
+        let (kind, data) = attributes.next().unwrap();
+        assert_eq!(kind, IFLA_XDP);
+        assert_eq!(data.len(), 16);
+        assert_eq!(attributes.next().unwrap().0, IFLA_MTU);
+
+        let link = LinkInfo::parse(payload).unwrap();
+        assert_eq!(link.xdp_programs().get(XdpMode::Skb), Some(9));
+        assert_eq!(link.index(), 3);
+    }
+
+    #[test]
+    fn xdp_flags() {
+        assert_eq!(XdpOptions::new().flags(), 0);
+
+        let options = XdpOptions::new().mode(XdpMode::Skb).if_noexist();
+        assert_eq!(
+            options.flags(),
+            XDP_FLAGS_SKB_MODE | XDP_FLAGS_UPDATE_IF_NOEXIST
+        );
+
+        let stdin = std::io::stdin();
+        let options = XdpOptions::new().replace(stdin.as_fd());
+        assert_eq!(options.flags(), XDP_FLAGS_REPLACE);
+    }
+
+    #[test]
+    fn truncated_message() {
+        let mut raw = Message::new(RTM_NEWLINK, 0).finish(1);
+        raw[0] = 64;
+
+        assert!(split_message(&raw).is_err());
+    }
+
+    #[test]
+    fn bridge_ports_are_skipped() {
+        let link = |kind, index, family| {
+            let mut header = ifinfomsg(index);
+            header[0] = family;
+
+            let mut message = Message::new(kind, 0);
+            message.push(&header);
+            message.attr_str(IFLA_IFNAME, "veth0").unwrap();
+            message.finish(0)
+        };
+
+        // The port leaves the bridge, then the interface goes down
+        let mut raw = link(RTM_DELLINK, 3, AF_BRIDGE as u8);
+        raw.extend(link(RTM_NEWLINK, 3, 0));
+
+        let mut links = HashMap::new();
+        let mut events = VecDeque::new();
+        link_events(&raw, &mut links, &mut events).unwrap();
+
+        assert_eq!(events.len(), 1);
+        assert!(matches!(&events[0], LinkEvent::New(link) if link.index() == 3));
+        assert!(links.contains_key(&3));
+    }
+}
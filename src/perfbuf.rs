@@ -0,0 +1,311 @@
+use crate::assert::{unsafe_no_panic, ExpectDefault, ExpectNonNegative, ExpectNotMapFailed};
+use crate::ringbuf::RecordSource;
+use crate::utility::{
+    load_acquire_u64, online_cpus, page_size, poll_dispatch, store_release_u64, Epoll,
+};
+use crate::{Error, Result};
+use libbpf_rs::{Map, MapFlags, MapType};
+use libc::{
+    c_int, c_ulong, epoll_event, ioctl, mmap, munmap, pid_t, syscall, SYS_perf_event_open,
+    MAP_SHARED, PROT_READ, PROT_WRITE,
+};
+use std::ffi::c_void;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr::null_mut;
+use std::slice;
+use std::task::{Context, Poll};
+use tokio::io::unix::AsyncFd;
+
+const PERF_BUFFER_DEFAULT_PAGES: usize = 64;
+
+const PERF_TYPE_SOFTWARE: u32 = 1;
+const PERF_COUNT_SW_BPF_OUTPUT: u64 = 10;
+const PERF_SAMPLE_RAW: u64 = 1 << 10;
+const PERF_FLAG_FD_CLOEXEC: c_ulong = 1 << 3;
+const PERF_EVENT_IOC_ENABLE: c_ulong = 0x2400;
+
+const PERF_RECORD_LOST: u32 = 2;
+const PERF_RECORD_SAMPLE: u32 = 9;
+const PERF_RECORD_HDR_SZ: usize = 8;
+
+/// Offsets of `data_head` and `data_tail` within `struct perf_event_mmap_page`
+const PERF_MMAP_DATA_HEAD: usize = 1024;
+const PERF_MMAP_DATA_TAIL: usize = 1032;
+
+/// Reads records written by BPF programs through `bpf_perf_event_output` from the per-CPU
+/// buffers of a perf event array. This is a fallback for kernels without BPF ring buffers.
+#[derive(Debug)]
+pub struct Perfbuf {
+    epoll: AsyncFd<Epoll>,
+    buffers: Vec<PerfCpuBuffer>,
+    events: Vec<epoll_event>,
+    scratch: Vec<u8>,
+    lost: u64,
+}
+
+unsafe impl Send for Perfbuf {}
+
+/// The perf event and memory mapped buffer of a single CPU
+#[derive(Debug)]
+struct PerfCpuBuffer {
+    fd: OwnedFd,
+    cpu: usize,
+    base: *mut c_void,
+    page_size: usize,
+    data_size: usize,
+}
+
+/// First version of `struct perf_event_attr`, which is all we need to set up BPF output events
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    kind: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+}
+
+impl Perfbuf {
+    /// Opens a perf buffer for every online CPU and registers it with the given perf event array
+    pub fn from_map(map: &Map) -> Result<Self> {
+        Self::with_pages(map, PERF_BUFFER_DEFAULT_PAGES)
+    }
+
+    /// Like `from_map`, with `pages` data pages per CPU, which must be a power of two
+    pub fn with_pages(map: &Map, pages: usize) -> Result<Self> {
+        if map.map_type() != MapType::PerfEventArray {
+            return Err(Error::WrongMapType {
+                expected: MapType::PerfEventArray,
+                found: map.map_type(),
+            });
+        }
+
+        if !pages.is_power_of_two() {
+            return Err(Error::PerfBufferSize { pages });
+        }
+
+        let max_entries = map.info()?.info.max_entries as usize;
+        let page_size = page_size()?;
+        let epoll = Epoll::new()?;
+        let mut buffers = vec![];
+
+        // The perf event array is indexed by CPU, so CPUs beyond its size can't be served
+        for cpu in online_cpus()?.into_iter().filter(|cpu| *cpu < max_entries) {
+            let buffer = PerfCpuBuffer::open(cpu, page_size, pages)?;
+
+            map.update(
+                &(cpu as u32).to_ne_bytes(),
+                &buffer.fd.as_raw_fd().to_ne_bytes(),
+                MapFlags::ANY,
+            )?;
+
+            epoll.add(&buffer.fd, buffers.len())?;
+            buffers.push(buffer);
+        }
+
+        Ok(Perfbuf {
+            epoll: AsyncFd::with_interest(epoll, tokio::io::Interest::READABLE)?,
+            events: vec![epoll_event { events: 0, u64: 0 }; buffers.len()],
+            buffers,
+            scratch: vec![],
+            lost: 0,
+        })
+    }
+
+    /// Returns the CPUs a buffer was opened for
+    pub fn cpus(&self) -> impl Iterator<Item = usize> + '_ {
+        self.buffers.iter().map(|buffer| buffer.cpu)
+    }
+
+    /// Returns the number of records the kernel dropped because a buffer was full
+    pub fn lost(&self) -> u64 {
+        self.lost
+    }
+}
+
+impl RecordSource for Perfbuf {
+    /// Raw samples are padded, so that the sample including its 4 byte size is 8 byte aligned
+    const PADDING: usize = 7;
+
+    fn try_drain<F>(&mut self, limit: usize, mut f: F) -> usize
+    where
+        F: FnMut(&[u8]),
+    {
+        let mut count = 0;
+
+        for buffer in &self.buffers {
+            count += buffer.consume(limit - count, &mut self.scratch, &mut self.lost, &mut f);
+
+            if count == limit {
+                break;
+            }
+        }
+
+        count
+    }
+
+    fn poll_drain<F>(
+        &mut self,
+        cx: &mut Context<'_>,
+        limit: usize,
+        mut f: F,
+    ) -> Poll<std::io::Result<usize>>
+    where
+        F: FnMut(&[u8]),
+    {
+        if self.buffers.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        poll_dispatch(&self.epoll, &mut self.events, cx, limit, |cpu, left| {
+            self.buffers[cpu as usize].consume(left, &mut self.scratch, &mut self.lost, &mut f)
+        })
+    }
+}
+
+impl PerfCpuBuffer {
+    fn open(cpu: usize, page_size: usize, pages: usize) -> Result<Self> {
+        let attr = PerfEventAttr {
+            kind: PERF_TYPE_SOFTWARE,
+            size: size_of::<PerfEventAttr>() as _,
+            config: PERF_COUNT_SW_BPF_OUTPUT,
+            sample_period: 1,
+            sample_type: PERF_SAMPLE_RAW,
+            wakeup_events: 1,
+            ..Default::default()
+        };
+
+        let fd = unsafe_no_panic!(syscall(
+            SYS_perf_event_open,
+            &attr as *const PerfEventAttr,
+            -1 as pid_t,
+            cpu as c_int,
+            -1 as c_int,
+            PERF_FLAG_FD_CLOEXEC,
+        ))
+        .expect(ExpectNonNegative, |os| Error::PerfEventOpen { cpu, os })?;
+
+        // SAFETY: File Descriptor was properly checked
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+
+        // The first page holds the metadata, followed by the data pages
+        let size = page_size * (pages + 1);
+        let base = unsafe_no_panic!(mmap(
+            null_mut(),
+            size,
+            PROT_READ | PROT_WRITE,
+            MAP_SHARED,
+            fd.as_raw_fd(),
+            0,
+        ))
+        .expect(ExpectNotMapFailed, |os| Error::PerfEventMmap {
+            cpu,
+            size,
+            os,
+        })?;
+
+        let buffer = PerfCpuBuffer {
+            fd,
+            cpu,
+            base,
+            page_size,
+            data_size: page_size * pages,
+        };
+
+        unsafe_no_panic!(ioctl(buffer.fd.as_raw_fd(), PERF_EVENT_IOC_ENABLE as _, 0))
+            .expect(ExpectDefault, |os| Error::PerfEventEnable { cpu, os })?;
+
+        Ok(buffer)
+    }
+
+    /// Hands over up to `limit` samples to `f` and counts lost records. Records that wrap around
+    /// the end of the buffer are copied to `scratch` first. The tail is written back once, after
+    /// all records were handed over.
+    fn consume<F>(&self, limit: usize, scratch: &mut Vec<u8>, lost: &mut u64, mut f: F) -> usize
+    where
+        F: FnMut(&[u8]),
+    {
+        let head_ptr = unsafe { self.base.add(PERF_MMAP_DATA_HEAD) } as *const u64;
+        let tail_ptr = unsafe { self.base.add(PERF_MMAP_DATA_TAIL) } as *mut u64;
+
+        let head = load_acquire_u64(head_ptr);
+        let start = load_acquire_u64(tail_ptr);
+
+        // SAFETY: The data pages were mapped right after the metadata page
+        let data = unsafe {
+            slice::from_raw_parts(self.base.add(self.page_size) as *const u8, self.data_size)
+        };
+
+        let mut tail = start;
+        let mut count = 0;
+
+        while count < limit && tail < head {
+            let offset = tail as usize & (self.data_size - 1);
+
+            // Records are 8 byte aligned, so the header itself never wraps around
+            let header = &data[offset..offset + PERF_RECORD_HDR_SZ];
+            let kind = u32::from_ne_bytes(header[0..4].try_into().unwrap());
+            let size = u16::from_ne_bytes(header[6..8].try_into().unwrap()) as usize;
+
+            if size < PERF_RECORD_HDR_SZ {
+                // A record can't be smaller than its header, so we skip everything we have
+                tail = head;
+                break;
+            }
+
+            let record = match offset + size <= self.data_size {
+                true => &data[offset..offset + size],
+                false => {
+                    let split = self.data_size - offset;
+
+                    scratch.clear();
+                    scratch.extend_from_slice(&data[offset..]);
+                    scratch.extend_from_slice(&data[..size - split]);
+                    &scratch[..]
+                }
+            };
+
+            match kind {
+                // Sample records carry a 4 byte size, followed by the raw data
+                PERF_RECORD_SAMPLE if record.len() >= PERF_RECORD_HDR_SZ + 4 => {
+                    let raw = &record[PERF_RECORD_HDR_SZ..];
+                    let len = u32::from_ne_bytes(raw[0..4].try_into().unwrap()) as usize;
+
+                    if let Some(sample) = raw.get(4..4 + len) {
+                        f(sample);
+                        count += 1;
+                    }
+                }
+                // Lost records carry an 8 byte id, followed by the number of lost records
+                PERF_RECORD_LOST if record.len() >= PERF_RECORD_HDR_SZ + 16 => {
+                    let raw = &record[PERF_RECORD_HDR_SZ + 8..PERF_RECORD_HDR_SZ + 16];
+                    *lost += u64::from_ne_bytes(raw.try_into().unwrap());
+                }
+                _ => {}
+            }
+
+            tail += size as u64;
+        }
+
+        // Publish the tail once for the whole batch
+        if tail != start {
+            store_release_u64(tail_ptr, tail);
+        }
+
+        count
+    }
+}
+
+impl Drop for PerfCpuBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.base, self.page_size + self.data_size);
+        }
+    }
+}
@@ -1,118 +1,752 @@
-use crate::assert::{unsafe_no_panic, ExpectNonNullPtr};
-use crate::utility::{page_size, AlignUp};
+use crate::assert::{unsafe_no_panic, ExpectNonNegative, ExpectNotMapFailed};
+use crate::utility::{
+    load_acquire_u32, load_acquire_u64, page_size, poll_dispatch, store_release_u64, AlignUp, Epoll,
+};
 use crate::{Error, Result};
-use futures::ready;
-use libbpf_rs::{Map, MapType};
-use libc::{mmap, MAP_SHARED, PROT_READ, PROT_WRITE};
-use std::ffi::{c_ulong, c_void};
+use futures::future::poll_fn;
+use futures::{ready, Stream};
+use libbpf_rs::libbpf_sys::bpf_obj_get;
+use libbpf_rs::{Map, MapInfo, MapType};
+use libc::{c_int, epoll_event, mmap, munmap, MAP_SHARED, PROT_READ, PROT_WRITE};
+use std::ffi::{c_void, CString};
 use std::io::ErrorKind;
-use std::os::fd::{AsFd, AsRawFd, BorrowedFd};
+use std::marker::PhantomData;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
 use std::pin::Pin;
 use std::ptr::null_mut;
 use std::slice;
-use std::sync::atomic::{compiler_fence, Ordering};
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 use tokio::io::unix::AsyncFd;
 use tokio::io::{AsyncRead, ReadBuf};
 
-const BPF_RINGBUF_BUSY_BIT: u32 = 1 << 31;
-const BPF_RINGBUF_DISCARD_BIT: u32 = 1 << 30;
-const BPF_RINGBUF_HDR_SZ: u32 = 8;
+#[cfg(test)]
+pub(crate) mod sim;
+
+pub(crate) const BPF_RINGBUF_BUSY_BIT: u32 = 1 << 31;
+pub(crate) const BPF_RINGBUF_DISCARD_BIT: u32 = 1 << 30;
+pub(crate) const BPF_RINGBUF_HDR_SZ: u32 = 8;
 
 #[derive(Debug)]
 pub struct Ringbuf<'a> {
-    fd: AsyncFd<BorrowedFd<'a>>,
-    mask: usize,
-    consumer: *mut c_void,
-    producer: *mut c_void,
-    data: *mut c_void,
+    fd: AsyncFd<RingFd<'a>>,
+    ring: Arc<RingMap>,
 }
 
-unsafe impl<'a> Send for Ringbuf<'a> {}
+/// A handle to the producer and consumer positions of a ring buffer. It can be used to watch the
+/// fill level while records are consumed elsewhere.
+#[derive(Debug, Clone)]
+pub struct RingbufStats {
+    ring: Arc<RingMap>,
+}
 
-impl<'a> Ringbuf<'a> {
-    fn new(
-        fd: BorrowedFd<'a>,
-        mask: usize,
-        consumer: *mut c_void,
-        producer: *mut c_void,
-        data: *mut c_void,
-    ) -> Self {
-        Ringbuf {
-            fd: AsyncFd::with_interest(fd, tokio::io::Interest::READABLE).unwrap(),
-            mask,
-            consumer,
-            producer,
-            data,
+/// A BPF ring buffer for consumers outside of tokio. Records are read after blocking in epoll, or
+/// by spinning on the producer position without any syscalls.
+#[derive(Debug)]
+pub struct BlockingRingbuf<'a> {
+    fd: RingFd<'a>,
+    epoll: Epoll,
+    events: [epoll_event; 1],
+    ring: RingMap,
+}
+
+/// File descriptor of a ring buffer Map. It is borrowed from a loaded object, or owned if the
+/// Map was opened from bpffs or received from another process.
+#[derive(Debug)]
+enum RingFd<'a> {
+    Borrowed(BorrowedFd<'a>),
+    Owned(OwnedFd),
+}
+
+/// Consumer page, producer page and data area of a BPF ring buffer or user ring buffer, mapped
+/// into memory
+#[derive(Debug)]
+pub(crate) struct RingMap {
+    pub(crate) mask: usize,
+    page_size: usize,
+    pub(crate) consumer: *mut c_void,
+    pub(crate) producer: *mut c_void,
+    pub(crate) data: *mut c_void,
+    /// Whether the pages are unmapped on drop. They are not if they belong to someone else, like
+    /// a simulated ring.
+    owned: bool,
+}
+
+unsafe impl Send for RingMap {}
+
+// SAFETY: Positions are only read with acquire loads and written with release stores, which
+// pairs with the kernel's smp_store_release/smp_load_acquire. The only position we write is the
+// one we advance ourselves: the consumer position of a ring buffer, through `&mut Ringbuf` (or a
+// `BlockingRingbuf` or `RingbufSet` member), or the producer position of a user ring buffer,
+// through `&mut UserRingbuf`. `RingbufStats` shares the map, but only reads.
+unsafe impl Sync for RingMap {}
+
+/// A set of BPF ring buffers that is polled through a single epoll instance. Records are handed
+/// over together with the id of the ring buffer they were read from.
+#[derive(Debug)]
+pub struct RingbufSet<'a> {
+    epoll: AsyncFd<Epoll>,
+    rings: Vec<SetMember<'a>>,
+    events: Vec<epoll_event>,
+}
+
+/// A ring buffer of a set. Its fd is only registered with the epoll instance of the set.
+#[derive(Debug)]
+struct SetMember<'a> {
+    fd: RingFd<'a>,
+    ring: Arc<RingMap>,
+}
+
+/// A fixed-size record written to a BPF ring buffer by a BPF program
+pub trait RingbufRecord: Sized {
+    /// Size of the record in bytes, as reserved by the BPF program
+    const SIZE: usize;
+
+    /// Decodes a record from a slice that is exactly `Self::SIZE` bytes long
+    fn decode(bytes: &[u8]) -> Result<Self>;
+
+    /// Checks the size of a raw record and decodes it
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Self::from_padded_bytes(bytes, 0)
+    }
+
+    /// Checks the size of a raw record that may carry up to `padding` trailing bytes, and decodes
+    /// it without the trailing bytes
+    fn from_padded_bytes(bytes: &[u8], padding: usize) -> Result<Self> {
+        if bytes.len() < Self::SIZE || bytes.len() - Self::SIZE > padding {
+            return Err(Error::RecordSize {
+                expected: Self::SIZE,
+                found: bytes.len(),
+            });
+        }
+
+        Self::decode(&bytes[..Self::SIZE])
+    }
+}
+
+/// A source of raw records written by BPF programs, such as a BPF ring buffer or a perf event
+/// array
+// Futures returned by the provided methods are only as Send as the source itself
+#[allow(async_fn_in_trait)]
+pub trait RecordSource {
+    /// Number of trailing bytes a raw record may carry in addition to the data written by the
+    /// BPF program
+    const PADDING: usize;
+
+    /// Consumes up to `limit` records that are ready, without waiting for new ones. Returns the
+    /// number of records handed over to `f`.
+    fn try_drain<F>(&mut self, limit: usize, f: F) -> usize
+    where
+        F: FnMut(&[u8]);
+
+    /// Polls for readiness until at least one record was consumed, then hands over up to `limit`
+    /// records to `f`
+    fn poll_drain<F>(
+        &mut self,
+        cx: &mut Context<'_>,
+        limit: usize,
+        f: F,
+    ) -> Poll<std::io::Result<usize>>
+    where
+        F: FnMut(&[u8]);
+
+    /// Waits until at least one record is ready, then consumes up to `limit` records. Returns
+    /// the number of records handed over to `f`.
+    async fn drain<F>(&mut self, limit: usize, mut f: F) -> std::io::Result<usize>
+    where
+        F: FnMut(&[u8]),
+    {
+        poll_fn(|cx| self.poll_drain(cx, limit, &mut f)).await
+    }
+
+    /// Waits until at least one record is ready, then decodes up to `limit` records into `out`.
+    /// Every drained record is consumed, even if decoding one of them fails. In that case the
+    /// first error is returned, and `out` holds all records that were decoded successfully.
+    async fn drain_into<R>(&mut self, limit: usize, out: &mut Vec<R>) -> Result<usize>
+    where
+        R: RingbufRecord,
+    {
+        let mut error = None;
+
+        let count = self
+            .drain(limit, |bytes| {
+                match R::from_padded_bytes(bytes, Self::PADDING) {
+                    Ok(record) => out.push(record),
+                    Err(err) => {
+                        error.get_or_insert(err);
+                    }
+                }
+            })
+            .await?;
+
+        match error {
+            Some(err) => Err(err),
+            None => Ok(count),
+        }
+    }
+
+    /// Turns the source into a stream of records of type `R`
+    fn into_stream<R>(self) -> RecordStream<Self, R>
+    where
+        Self: Sized,
+        R: RingbufRecord,
+    {
+        RecordStream {
+            source: self,
+            record: PhantomData,
         }
     }
+}
+
+/// A stream of typed records read from a record source
+#[derive(Debug)]
+pub struct RecordStream<S, R> {
+    source: S,
+    record: PhantomData<fn() -> R>,
+}
+
+/// A stream of typed records read from a BPF ring buffer
+pub type RingbufStream<'a, R> = RecordStream<Ringbuf<'a>, R>;
+
+impl<'a> Ringbuf<'a> {
+    fn new(fd: RingFd<'a>) -> Result<Self> {
+        let ring = RingMap::from_fd(fd.as_fd(), MapType::RingBuf)?;
+
+        Ok(Ringbuf {
+            fd: AsyncFd::with_interest(fd, tokio::io::Interest::READABLE)?,
+            ring: Arc::new(ring),
+        })
+    }
 
     /// Returns a BPF ring buffer from a given Map
     pub fn from_map(map: &'a Map) -> Result<Self> {
-        if map.map_type() != MapType::RingBuf {
-            return Err(Error::WrongMapType)?;
+        Self::new(RingFd::Borrowed(map.as_fd()))
+    }
+
+    /// Returns a BPF ring buffer from the file descriptor of a ring buffer Map, e.g. one that
+    /// was received from another process
+    pub fn from_fd(fd: OwnedFd) -> Result<Ringbuf<'static>> {
+        Ringbuf::new(RingFd::Owned(fd))
+    }
+
+    /// Returns a BPF ring buffer from a ring buffer Map pinned to bpffs
+    pub fn from_pinned<P>(path: P) -> Result<Ringbuf<'static>>
+    where
+        P: AsRef<Path>,
+    {
+        Ringbuf::new(RingFd::from_pinned(path)?)
+    }
+
+    /// Returns the file descriptor associated with the ring buffer
+    pub fn fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+
+    /// Returns the length of the next record without consuming it, or `None` if no record is
+    /// ready
+    pub fn peek_len(&self) -> Option<usize> {
+        self.ring.peek_len()
+    }
+
+    /// Consumes the next record without reading it. Returns false if no record was ready.
+    pub fn skip(&mut self) -> bool {
+        self.ring.consume(1, |_| {}) == 1
+    }
+
+    /// Returns the position up to which the kernel has reserved records
+    pub fn producer_pos(&self) -> u64 {
+        self.ring.producer_pos()
+    }
+
+    /// Returns the position up to which records were consumed
+    pub fn consumer_pos(&self) -> u64 {
+        self.ring.consumer_pos()
+    }
+
+    /// Returns the number of bytes that were reserved by the kernel, but not consumed yet
+    pub fn pending(&self) -> u64 {
+        self.ring.pending()
+    }
+
+    /// Returns the size of the data area in bytes
+    pub fn capacity(&self) -> u64 {
+        self.ring.capacity()
+    }
+
+    /// Returns a handle to watch the fill level of the ring buffer from another task, while this
+    /// one consumes records
+    pub fn stats(&self) -> RingbufStats {
+        RingbufStats {
+            ring: Arc::clone(&self.ring),
+        }
+    }
+}
+
+impl RingbufStats {
+    /// Returns the position up to which the kernel has reserved records
+    pub fn producer_pos(&self) -> u64 {
+        self.ring.producer_pos()
+    }
+
+    /// Returns the position up to which records were consumed
+    pub fn consumer_pos(&self) -> u64 {
+        self.ring.consumer_pos()
+    }
+
+    /// Returns the number of bytes that were reserved by the kernel, but not consumed yet
+    pub fn pending(&self) -> u64 {
+        self.ring.pending()
+    }
+
+    /// Returns the size of the data area in bytes
+    pub fn capacity(&self) -> u64 {
+        self.ring.capacity()
+    }
+}
+
+impl<'a> BlockingRingbuf<'a> {
+    fn new(fd: RingFd<'a>) -> Result<Self> {
+        let ring = RingMap::from_fd(fd.as_fd(), MapType::RingBuf)?;
+        let epoll = Epoll::new()?;
+
+        epoll.add(&fd, 0)?;
+
+        Ok(BlockingRingbuf {
+            fd,
+            epoll,
+            events: [epoll_event { events: 0, u64: 0 }],
+            ring,
+        })
+    }
+
+    /// Returns a BPF ring buffer from a given Map. No tokio runtime is required.
+    pub fn from_map(map: &'a Map) -> Result<Self> {
+        Self::new(RingFd::Borrowed(map.as_fd()))
+    }
+
+    /// Returns a BPF ring buffer from the file descriptor of a ring buffer Map, e.g. one that
+    /// was received from another process
+    pub fn from_fd(fd: OwnedFd) -> Result<BlockingRingbuf<'static>> {
+        BlockingRingbuf::new(RingFd::Owned(fd))
+    }
+
+    /// Returns a BPF ring buffer from a ring buffer Map pinned to bpffs
+    pub fn from_pinned<P>(path: P) -> Result<BlockingRingbuf<'static>>
+    where
+        P: AsRef<Path>,
+    {
+        BlockingRingbuf::new(RingFd::from_pinned(path)?)
+    }
+
+    /// Returns the file descriptor associated with the ring buffer
+    pub fn fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+
+    /// Waits up to `timeout` for records, or indefinitely if `timeout` is `None`, then consumes
+    /// every record that is ready. Returns the number of records handed over to `f`, which is
+    /// only zero if the timeout expired.
+    pub fn poll<F>(&mut self, timeout: Option<Duration>, mut f: F) -> std::io::Result<usize>
+    where
+        F: FnMut(&[u8]),
+    {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            let timeout = match deadline {
+                // Round up, so we don't wake up right before the deadline over and over again
+                Some(deadline) => deadline
+                    .saturating_duration_since(Instant::now())
+                    .as_micros()
+                    .div_ceil(1000)
+                    .try_into()
+                    .unwrap_or(c_int::MAX),
+                None => -1,
+            };
+
+            // The ring buffer is registered level-triggered, so records we already left behind
+            // are reported right away
+            let ready = !self.epoll.wait(&mut self.events, timeout)?.is_empty();
+
+            let count = self.consume(&mut f);
+
+            if count > 0 || deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Ok(count);
+            }
+
+            // The next record is still being written by the kernel. It keeps the ring buffer
+            // readable, so we give the producer a chance to commit it instead of spinning in
+            // epoll.
+            if ready {
+                std::thread::yield_now();
+            }
+        }
+    }
+
+    /// Returns the length of the next record without consuming it, or `None` if no record is
+    /// ready
+    pub fn peek_len(&self) -> Option<usize> {
+        self.ring.peek_len()
+    }
+
+    /// Consumes the next record without reading it. Returns false if no record was ready.
+    pub fn skip(&mut self) -> bool {
+        self.ring.consume(1, |_| {}) == 1
+    }
+
+    /// Consumes every record that is ready, without waiting for new ones. Returns the number of
+    /// records handed over to `f`.
+    pub fn consume<F>(&mut self, f: F) -> usize
+    where
+        F: FnMut(&[u8]),
+    {
+        self.ring.consume(usize::MAX, f)
+    }
+
+    /// Busy-polls the ring buffer without any syscalls until at least one record is ready, or
+    /// until `timeout` expired, then consumes every record that is ready. Returns the number of
+    /// records handed over to `f`, which is zero if the timeout expired.
+    pub fn spin<F>(&mut self, timeout: Option<Duration>, mut f: F) -> usize
+    where
+        F: FnMut(&[u8]),
+    {
+        let start = Instant::now();
+
+        loop {
+            let count = self.ring.consume(usize::MAX, &mut f);
+
+            if count > 0 || timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+                return count;
+            }
+
+            std::hint::spin_loop();
+        }
+    }
+}
+
+impl RingFd<'static> {
+    /// Opens a Map pinned to bpffs
+    fn from_pinned<P>(path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let cpath = CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| Error::InvalidName(path.display().to_string()))?;
+
+        let fd = unsafe_no_panic!(bpf_obj_get(cpath.as_ptr())).expect(ExpectNonNegative, |os| {
+            Error::PinnedMap {
+                path: path.to_path_buf(),
+                os,
+            }
+        })?;
+
+        // SAFETY: File Descriptor was properly checked
+        Ok(RingFd::Owned(unsafe { OwnedFd::from_raw_fd(fd) }))
+    }
+}
+
+impl<'a> AsFd for RingFd<'a> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            RingFd::Borrowed(fd) => fd.as_fd(),
+            RingFd::Owned(fd) => fd.as_fd(),
+        }
+    }
+}
+
+impl<'a> AsRawFd for RingFd<'a> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.as_fd().as_raw_fd()
+    }
+}
+
+impl RingMap {
+    /// Maps the consumer page, producer page and data area of a ring buffer or user ring buffer
+    /// Map. Type and size of the Map are queried from the kernel, as the Map might not come from
+    /// a loaded object.
+    pub(crate) fn from_fd(fd: BorrowedFd<'_>, map_type: MapType) -> Result<Self> {
+        let info = MapInfo::new(fd)?;
+
+        if info.map_type() != map_type {
+            return Err(Error::WrongMapType {
+                expected: map_type,
+                found: info.map_type(),
+            });
+        }
+
+        // Only the position we advance ourselves is writable, the kernel owns the other one
+        let (consumer_prot, producer_prot) = match map_type {
+            MapType::UserRingBuf => (PROT_READ, PROT_READ | PROT_WRITE),
+            _ => (PROT_READ | PROT_WRITE, PROT_READ),
+        };
+
+        // The kernel only creates ring buffers with a power of two number of bytes
+        let max_entries = info.info.max_entries;
+        if !max_entries.is_power_of_two() {
+            return Err(Error::RingbufSize { size: max_entries });
         }
 
-        let max_entries = map
-            .info()
-            .expect("failed getting map info")
-            .info
-            .max_entries;
-        let mask = max_entries.checked_sub(1).expect("ring buf was empty") as usize;
-        let page_size = unsafe { page_size()? };
+        let mask = (max_entries - 1) as usize;
+        let page_size = page_size()?;
         let mmap_sz: usize = page_size + 2 * (max_entries as usize);
 
         let consumer = unsafe_no_panic!(mmap(
             null_mut(),
             page_size,
-            PROT_READ | PROT_WRITE,
+            consumer_prot,
             MAP_SHARED,
-            map.as_fd().as_raw_fd(),
+            fd.as_raw_fd(),
             0,
         ))
-        .expect(ExpectNonNullPtr, Error::ConsumerMmap)?;
+        .expect(ExpectNotMapFailed, |os| Error::ConsumerMmap {
+            fd: fd.as_raw_fd(),
+            os,
+        })?;
 
         let producer = unsafe_no_panic!(mmap(
             null_mut(),
             mmap_sz,
-            PROT_READ,
+            producer_prot,
             MAP_SHARED,
-            map.as_fd().as_raw_fd(),
+            fd.as_raw_fd(),
             page_size as _,
         ))
-        .expect(ExpectNonNullPtr, Error::ProducerMmap)?;
+        .expect(ExpectNotMapFailed, |os| Error::ProducerMmap {
+            fd: fd.as_raw_fd(),
+            size: mmap_sz,
+            os,
+        });
+
+        let producer = match producer {
+            Ok(producer) => producer,
+            Err(err) => {
+                unsafe { munmap(consumer, page_size) };
+                return Err(err);
+            }
+        };
 
-        Ok(Self::new(map.as_fd(), mask, consumer, producer, unsafe {
-            producer.add(page_size)
-        }))
+        Ok(RingMap {
+            mask,
+            page_size,
+            consumer,
+            producer,
+            data: unsafe { producer.add(page_size) },
+            owned: true,
+        })
     }
 
-    /// Returns the file descriptor associated with the ring buffer
-    pub fn fd(&self) -> BorrowedFd<'_> {
-        self.fd.as_fd()
+    fn producer_pos(&self) -> u64 {
+        load_acquire_u64(self.producer as *const u64)
     }
-}
 
-/// Reads bytes from the given pointer and adds a memory barrier. This should be used with Acquire
-#[inline(always)]
-fn read_volatile_fence<T>(ptr: *const T, ordering: Ordering) -> T {
-    let val = unsafe { std::ptr::read_volatile(ptr) };
-    compiler_fence(ordering);
+    fn consumer_pos(&self) -> u64 {
+        load_acquire_u64(self.consumer as *const u64)
+    }
+
+    /// Both positions are read separately, so the consumer position is read first. That way
+    /// the consumer can't overtake the producer in between.
+    fn pending(&self) -> u64 {
+        let consumer_pos = self.consumer_pos();
+
+        self.producer_pos().saturating_sub(consumer_pos)
+    }
+
+    fn capacity(&self) -> u64 {
+        self.mask as u64 + 1
+    }
 
-    val
+    /// Returns the length of the next committed record, skipping discarded ones. Nothing is
+    /// consumed.
+    fn peek_len(&self) -> Option<usize> {
+        let mut consumer_pos = load_acquire_u64(self.consumer as *const u64);
+        let producer_pos = load_acquire_u64(self.producer as *const u64);
+
+        while consumer_pos < producer_pos {
+            let len_ptr = unsafe { self.data.add(consumer_pos as usize & self.mask) };
+            let len = load_acquire_u32(len_ptr as *const u32);
+
+            if len & BPF_RINGBUF_BUSY_BIT != 0 {
+                return None;
+            }
+
+            if len & BPF_RINGBUF_DISCARD_BIT == 0 {
+                return Some(len as usize);
+            }
+
+            consumer_pos += roundup_len(len) as u64;
+        }
+
+        None
+    }
+
+    /// Hands over up to `limit` committed records to `f`. Stops early at a record that is still
+    /// being written by the kernel. The consumer position is written back once, after all
+    /// records were handed over.
+    fn consume<F>(&self, limit: usize, mut f: F) -> usize
+    where
+        F: FnMut(&[u8]),
+    {
+        let start_pos = load_acquire_u64(self.consumer as *const u64);
+        let producer_pos = load_acquire_u64(self.producer as *const u64);
+
+        let mut consumer_pos = start_pos;
+        let mut count = 0;
+
+        while count < limit && consumer_pos < producer_pos {
+            // Get a pointer to the header of the next object
+            let len_ptr = unsafe { self.data.add(consumer_pos as usize & self.mask) };
+            let len = load_acquire_u32(len_ptr as *const u32);
+
+            // Records have to be consumed in order, so we have to stop at the first element
+            // that is still being written by the kernel
+            if len & BPF_RINGBUF_BUSY_BIT != 0 {
+                break;
+            }
+
+            consumer_pos += roundup_len(len) as u64;
+
+            // Discarded elements are skipped and don't count towards the limit
+            if len & BPF_RINGBUF_DISCARD_BIT != 0 {
+                continue;
+            }
+
+            let data = unsafe { len_ptr.add(BPF_RINGBUF_HDR_SZ as usize) };
+            f(unsafe { slice::from_raw_parts(data as *const u8, len as usize) });
+            count += 1;
+        }
+
+        // Publish the consumer position once for the whole batch
+        if consumer_pos != start_pos {
+            store_release_u64(self.consumer as *mut u64, consumer_pos);
+        }
+
+        count
+    }
 }
 
-/// Writes bytes to the given pointer and adds a memory barrier. This should be used with Release
-#[inline(always)]
-fn write_volatile_fence<T>(ptr: *mut T, val: T, ordering: Ordering) {
-    compiler_fence(ordering);
-    unsafe { std::ptr::write_volatile(ptr, val) };
+impl Drop for RingMap {
+    fn drop(&mut self) {
+        // The mappings keep the Map alive in the kernel, even after its fd was closed
+        if self.owned {
+            unsafe {
+                munmap(self.consumer, self.page_size);
+                munmap(self.producer, self.page_size + 2 * (self.mask + 1));
+            }
+        }
+    }
+}
+
+impl<'a> RingbufSet<'a> {
+    /// Returns an empty set of ring buffers
+    pub fn new() -> Result<Self> {
+        Ok(RingbufSet {
+            epoll: AsyncFd::with_interest(Epoll::new()?, tokio::io::Interest::READABLE)?,
+            rings: vec![],
+            events: vec![],
+        })
+    }
+
+    /// Adds a ring buffer Map to the set and returns the id its records are dispatched with. Ids
+    /// are assigned in the order ring buffers are added, starting at zero.
+    pub fn add_map(&mut self, map: &'a Map) -> Result<usize> {
+        self.add(RingFd::Borrowed(map.as_fd()))
+    }
+
+    /// Adds the file descriptor of a ring buffer Map to the set, e.g. one that was received from
+    /// another process
+    pub fn add_fd(&mut self, fd: OwnedFd) -> Result<usize> {
+        self.add(RingFd::Owned(fd))
+    }
+
+    /// Adds a ring buffer Map pinned to bpffs to the set
+    pub fn add_pinned<P>(&mut self, path: P) -> Result<usize>
+    where
+        P: AsRef<Path>,
+    {
+        self.add(RingFd::from_pinned(path)?)
+    }
+
+    /// Returns the file descriptor of the ring buffer with the given id
+    pub fn fd(&self, id: usize) -> Option<BorrowedFd<'_>> {
+        self.rings.get(id).map(|member| member.fd.as_fd())
+    }
+
+    /// Returns a handle to watch the fill level of the ring buffer with the given id
+    pub fn stats(&self, id: usize) -> Option<RingbufStats> {
+        self.rings.get(id).map(|member| RingbufStats {
+            ring: Arc::clone(&member.ring),
+        })
+    }
+
+    /// Returns the number of ring buffers in the set
+    pub fn len(&self) -> usize {
+        self.rings.len()
+    }
+
+    /// Returns true if no ring buffer was added to the set
+    pub fn is_empty(&self) -> bool {
+        self.rings.is_empty()
+    }
+
+    fn add(&mut self, fd: RingFd<'a>) -> Result<usize> {
+        let ring = RingMap::from_fd(fd.as_fd(), MapType::RingBuf)?;
+
+        self.push(fd, ring)
+    }
+
+    fn push(&mut self, fd: RingFd<'a>, ring: RingMap) -> Result<usize> {
+        let id = self.rings.len();
+
+        self.epoll.get_ref().add(&fd, id)?;
+
+        self.rings.push(SetMember {
+            fd,
+            ring: Arc::new(ring),
+        });
+        self.events.push(epoll_event { events: 0, u64: 0 });
+
+        Ok(id)
+    }
+
+    /// Waits until at least one ring buffer has records, then consumes up to `limit` records
+    /// across all ready ring buffers. Returns the number of records handed over to `f`.
+    pub async fn drain<F>(&mut self, limit: usize, mut f: F) -> std::io::Result<usize>
+    where
+        F: FnMut(usize, &[u8]),
+    {
+        poll_fn(|cx| self.poll_drain(cx, limit, &mut f)).await
+    }
+
+    /// Polls the epoll instance for readiness until at least one record was consumed
+    pub fn poll_drain<F>(
+        &mut self,
+        cx: &mut Context<'_>,
+        limit: usize,
+        mut f: F,
+    ) -> Poll<std::io::Result<usize>>
+    where
+        F: FnMut(usize, &[u8]),
+    {
+        if self.rings.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        poll_dispatch(&self.epoll, &mut self.events, cx, limit, |id, left| {
+            let id = id as usize;
+
+            self.rings[id].ring.consume(left, |bytes| f(id, bytes))
+        })
+    }
 }
 
 /// Given a ring buffer header, removes the Busy and Discard bits, then adds the length of the BPF
 /// header and aligns it to a byte boundary
 #[inline(always)]
-fn roundup_len(mut len: u32) -> u32 {
+pub(crate) fn roundup_len(mut len: u32) -> u32 {
     len <<= 2;
     len >>= 2;
     len += BPF_RINGBUF_HDR_SZ;
@@ -121,86 +755,354 @@ fn roundup_len(mut len: u32) -> u32 {
 }
 
 impl<'a> AsyncRead for Ringbuf<'a> {
+    /// Reads the next record. If the record doesn't fit into `buf`, `WriteZero` is returned and
+    /// the record is kept, so it can be read with a larger buffer or skipped. Empty records are
+    /// skipped, as reading zero bytes signals the end of the stream.
     fn poll_read(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<std::io::Result<()>> {
-        // Read consumer position
-        let mut consumer_pos =
-            read_volatile_fence(self.consumer as *const c_ulong, Ordering::Acquire);
-
         loop {
-            // Read producer position
-            let producer_pos =
-                read_volatile_fence(self.producer as *const c_ulong, Ordering::Acquire);
-
-            // Check if we read every element from the ring buffer
-            if consumer_pos == producer_pos {
-                // Poll for readiness.
-                let mut guard = ready!(self.fd.poll_read_ready(cx))?;
-
-                // If that's the case, we clear the readiness state and rely on the kernel to send
-                // a notification once a new element is ready.
-                guard.clear_ready();
-
-                // We immediately continue to test if there are new elements in the ring buffer.
-                // If this is the case, the kernel should have sent us a notification, so the next
-                // producer position has increased.
-                // If this is not the case, we will be getting Poll::Pending from the next poll_read_ready
-                // and register the waker again with tokio
-                continue;
+            match self.ring.peek_len() {
+                Some(0) => {
+                    self.ring.consume(1, |_| {});
+                }
+                // Don't consume records we can't hand over as a whole
+                Some(len) if len > buf.remaining() => {
+                    return Poll::Ready(Err(ErrorKind::WriteZero.into()))
+                }
+                Some(_) => {
+                    self.ring.consume(1, |slice| buf.put_slice(slice));
+
+                    // Hand data over to the consumer
+                    return Poll::Ready(Ok(()));
+                }
+                None => {
+                    // Nothing was ready, so we clear the readiness state and wait for the kernel
+                    // to notify us about new elements
+                    let mut guard = ready!(self.fd.poll_read_ready(cx))?;
+                    guard.clear_ready();
+                }
             }
+        }
+    }
+}
 
-            // Get a pointer to the header of the next object
-            let len_ptr = unsafe { self.data.add(consumer_pos as usize & self.mask) };
+impl<'a> RecordSource for Ringbuf<'a> {
+    const PADDING: usize = 0;
 
-            // Read only the header byte
-            let len = read_volatile_fence(len_ptr as *const u32, Ordering::Acquire);
+    fn try_drain<F>(&mut self, limit: usize, f: F) -> usize
+    where
+        F: FnMut(&[u8]),
+    {
+        self.ring.consume(limit, f)
+    }
 
-            // If this element is currently being written by the kernel, we immediately continue
-            // and try again
-            if len & BPF_RINGBUF_BUSY_BIT != 0 {
-                continue;
+    fn poll_drain<F>(
+        &mut self,
+        cx: &mut Context<'_>,
+        limit: usize,
+        mut f: F,
+    ) -> Poll<std::io::Result<usize>>
+    where
+        F: FnMut(&[u8]),
+    {
+        loop {
+            let count = self.ring.consume(limit, &mut f);
+
+            if count > 0 || limit == 0 {
+                return Poll::Ready(Ok(count));
             }
 
-            // Increase consumer position, but don't write it yet
-            consumer_pos += roundup_len(len) as u64;
+            // Nothing was ready, so we clear the readiness state and wait for the kernel to
+            // notify us about new elements
+            let mut guard = ready!(self.fd.poll_read_ready(cx))?;
+            guard.clear_ready();
+        }
+    }
+}
 
-            // If the discard bit is set we write the consumer position and try to get the next
-            // element
-            if len & BPF_RINGBUF_DISCARD_BIT != 0 {
-                write_volatile_fence(
-                    self.consumer as *mut c_ulong,
-                    consumer_pos,
-                    Ordering::Release,
-                );
+impl<S, R> Stream for RecordStream<S, R>
+where
+    S: RecordSource + Unpin,
+    R: RingbufRecord,
+{
+    type Item = Result<R>;
 
-                continue;
-            }
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut record = None;
 
-            // We get a pointer to the actual message payload and create a slice to it
-            let data = unsafe { len_ptr.add(BPF_RINGBUF_HDR_SZ as usize) };
-            let slice: &[u8] = unsafe { slice::from_raw_parts(data as *const u8, len as usize) };
+        ready!(self.get_mut().source.poll_drain(cx, 1, |bytes| {
+            record = Some(R::from_padded_bytes(bytes, S::PADDING));
+        }))?;
 
-            // Write slice to buffer if we have enough capacity
-            let result = match buf.capacity() >= slice.len() {
-                true => {
-                    buf.put_slice(slice);
-                    Ok(())
-                }
-                false => Err(ErrorKind::WriteZero.into()),
-            };
+        Poll::Ready(record)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ringbuf::sim::SimRing;
+    use crate::ringbuf::{RecordSource, RingbufSet, BPF_RINGBUF_HDR_SZ};
+    use std::io::ErrorKind;
+    use std::time::{Duration, Instant};
+    use tokio::io::AsyncReadExt;
+
+    const SIZE: usize = 4096;
+
+    #[tokio::test]
+    async fn oversized_record_is_kept() {
+        let sim = SimRing::new(SIZE);
+        let mut ringbuf = sim.ringbuf();
+
+        sim.produce(&[1; 16]);
+
+        let mut small = [0u8; 8];
+        let err = ringbuf.read(&mut small).await.unwrap_err();
+
+        assert_eq!(err.kind(), ErrorKind::WriteZero);
+        assert_eq!(sim.consumer_pos(), 0);
+        assert_eq!(ringbuf.peek_len(), Some(16));
+
+        let mut large = [0u8; 16];
+        assert_eq!(ringbuf.read(&mut large).await.unwrap(), 16);
+        assert_eq!(large, [1; 16]);
+        assert_eq!(ringbuf.peek_len(), None);
+    }
+
+    #[tokio::test]
+    async fn oversized_record_is_skipped() {
+        let sim = SimRing::new(SIZE);
+        let mut ringbuf = sim.ringbuf();
+
+        sim.produce(&[1; 16]);
+        sim.produce(&[2; 4]);
+
+        let mut small = [0u8; 8];
+        let err = ringbuf.read(&mut small).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WriteZero);
+
+        assert!(ringbuf.skip());
+        assert_eq!(ringbuf.peek_len(), Some(4));
+        assert_eq!(ringbuf.read(&mut small).await.unwrap(), 4);
+        assert_eq!(small[..4], [2; 4]);
+    }
+
+    #[tokio::test]
+    async fn records_are_rounded_up() {
+        let sim = SimRing::new(SIZE);
+        let mut ringbuf = sim.ringbuf();
+
+        sim.produce(&[1; 5]);
+        sim.produce(&[2; 8]);
+
+        let mut records = vec![];
+        assert_eq!(
+            ringbuf.try_drain(usize::MAX, |r| records.push(r.to_vec())),
+            2
+        );
+        assert_eq!(records, vec![vec![1; 5], vec![2; 8]]);
+        assert_eq!(sim.consumer_pos(), 2 * (BPF_RINGBUF_HDR_SZ as u64 + 8));
+        assert_eq!(sim.consumer_pos(), sim.producer_pos());
+    }
+
+    #[tokio::test]
+    async fn busy_record_blocks_later_records() {
+        let sim = SimRing::new(SIZE);
+        let mut ringbuf = sim.ringbuf();
+
+        let busy = sim.reserve(4).unwrap();
+        sim.produce(&[2; 4]);
+
+        assert_eq!(ringbuf.peek_len(), None);
+        assert_eq!(ringbuf.try_drain(usize::MAX, |_| {}), 0);
+        assert_eq!(sim.consumer_pos(), 0);
+
+        sim.fill(&busy, &[1; 4]);
+        sim.commit(busy);
+
+        let mut records = vec![];
+        assert_eq!(
+            ringbuf.try_drain(usize::MAX, |r| records.push(r.to_vec())),
+            2
+        );
+        assert_eq!(records, vec![vec![1; 4], vec![2; 4]]);
+    }
+
+    #[tokio::test]
+    async fn discarded_records_are_skipped() {
+        let sim = SimRing::new(SIZE);
+        let mut ringbuf = sim.ringbuf();
+
+        let discarded = sim.reserve(16).unwrap();
+        sim.discard(discarded);
+        sim.produce(&[2; 4]);
+
+        assert_eq!(ringbuf.peek_len(), Some(4));
+
+        let mut records = vec![];
+        assert_eq!(
+            ringbuf.try_drain(usize::MAX, |r| records.push(r.to_vec())),
+            1
+        );
+        assert_eq!(records, vec![vec![2; 4]]);
+        assert_eq!(sim.consumer_pos(), sim.producer_pos());
+    }
 
-            // Write consumer position back to ring buffer
-            write_volatile_fence(
-                self.consumer as *mut c_ulong,
-                consumer_pos,
-                Ordering::Release,
-            );
+    #[tokio::test]
+    async fn record_wraps_around() {
+        let sim = SimRing::new(SIZE);
+        let mut ringbuf = sim.ringbuf();
 
-            // Hand data over to the consumer
-            return Poll::Ready(result);
+        // The header fits before the end of the data area, the payload doesn't
+        sim.seek(SIZE as u64 - BPF_RINGBUF_HDR_SZ as u64);
+
+        let payload: Vec<u8> = (0..64).collect();
+        sim.produce(&payload);
+
+        let mut buf = [0u8; 64];
+        assert_eq!(ringbuf.read(&mut buf).await.unwrap(), 64);
+        assert_eq!(buf[..], payload[..]);
+        assert_eq!(sim.consumer_pos(), SIZE as u64 + 64);
+    }
+
+    #[tokio::test]
+    async fn full_ring_rejects_records() {
+        let sim = SimRing::new(SIZE);
+        let ringbuf = sim.ringbuf();
+
+        let payload = [0u8; SIZE / 2 - BPF_RINGBUF_HDR_SZ as usize];
+
+        assert!(sim.produce(&payload));
+        assert!(sim.produce(&payload));
+        assert!(!sim.produce(&[0; 1]));
+
+        assert_eq!(ringbuf.pending(), SIZE as u64);
+        assert_eq!(ringbuf.capacity(), SIZE as u64);
+        assert_eq!(ringbuf.producer_pos(), SIZE as u64);
+        assert_eq!(ringbuf.consumer_pos(), 0);
+
+        // Stats share the positions with the ring buffer
+        assert_eq!(ringbuf.stats().pending(), ringbuf.pending());
+    }
+
+    #[tokio::test]
+    async fn read_waits_for_producer() {
+        let sim = SimRing::new(SIZE);
+        let mut ringbuf = sim.ringbuf();
+
+        let mut buf = [0u8; 4];
+        let (read, _) = tokio::join!(ringbuf.read(&mut buf), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            sim.produce(&[3; 4]);
+        });
+
+        assert_eq!(read.unwrap(), 4);
+        assert_eq!(buf, [3; 4]);
+    }
+
+    #[tokio::test]
+    async fn set_dispatches_by_id() {
+        let first = SimRing::new(SIZE);
+        let second = SimRing::new(SIZE);
+
+        let mut set = RingbufSet::new().unwrap();
+        assert_eq!(first.add_to(&mut set), 0);
+        assert_eq!(second.add_to(&mut set), 1);
+
+        second.produce(&[2; 4]);
+        first.produce(&[1; 4]);
+        second.produce(&[3; 4]);
+
+        let mut records = vec![];
+        let count = set
+            .drain(usize::MAX, |id, r| records.push((id, r.to_vec())))
+            .await
+            .unwrap();
+
+        records.sort();
+        assert_eq!(count, 3);
+        assert_eq!(
+            records,
+            vec![(0, vec![1; 4]), (1, vec![2; 4]), (1, vec![3; 4])]
+        );
+        assert_eq!(set.stats(1).unwrap().pending(), 0);
+    }
+
+    #[tokio::test]
+    async fn set_keeps_records_beyond_limit() {
+        let first = SimRing::new(SIZE);
+        let second = SimRing::new(SIZE);
+
+        let mut set = RingbufSet::new().unwrap();
+        first.add_to(&mut set);
+        second.add_to(&mut set);
+
+        for _ in 0..3 {
+            first.produce(&[1; 4]);
+            second.produce(&[2; 4]);
+        }
+
+        let mut seen = 0;
+        for _ in 0..3 {
+            assert_eq!(set.drain(2, |_, _| seen += 1).await.unwrap(), 2);
         }
+
+        assert_eq!(seen, 6);
+        assert_eq!(first.consumer_pos(), first.producer_pos());
+        assert_eq!(second.consumer_pos(), second.producer_pos());
+    }
+
+    #[tokio::test]
+    async fn set_waits_for_producer() {
+        let sim = SimRing::new(SIZE);
+
+        let mut set = RingbufSet::new().unwrap();
+        sim.add_to(&mut set);
+
+        let mut records = vec![];
+        let (count, _) = tokio::join!(
+            set.drain(usize::MAX, |id, r| records.push((id, r[0]))),
+            async {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                sim.produce(&[7; 4]);
+            }
+        );
+
+        assert_eq!(count.unwrap(), 1);
+        assert_eq!(records, vec![(0, 7)]);
+    }
+
+    #[test]
+    fn blocking_poll_waits_for_busy_record() {
+        let sim = SimRing::new(SIZE);
+        let mut ringbuf = sim.blocking_ringbuf();
+
+        sim.produce(&[1; 4]);
+        assert_eq!(ringbuf.poll(None, |_| {}).unwrap(), 1);
+
+        // The notification of the first record keeps the ring buffer readable
+        let _busy = sim.reserve(4).unwrap();
+
+        let timeout = Duration::from_millis(20);
+        let start = Instant::now();
+
+        assert_eq!(ringbuf.poll(Some(timeout), |_| {}).unwrap(), 0);
+        assert!(start.elapsed() >= timeout);
+    }
+
+    #[tokio::test]
+    async fn empty_record_is_skipped() {
+        let sim = SimRing::new(SIZE);
+        let mut ringbuf = sim.ringbuf();
+
+        sim.produce(&[]);
+        sim.produce(&[5; 4]);
+
+        let mut buf = [0u8; 4];
+        assert_eq!(ringbuf.read(&mut buf).await.unwrap(), 4);
+        assert_eq!(buf, [5; 4]);
+        assert_eq!(sim.consumer_pos(), sim.producer_pos());
     }
 }
@@ -0,0 +1,307 @@
+use crate::ringbuf::{
+    roundup_len, BlockingRingbuf, RingFd, RingMap, Ringbuf, RingbufSet, BPF_RINGBUF_BUSY_BIT,
+    BPF_RINGBUF_DISCARD_BIT, BPF_RINGBUF_HDR_SZ,
+};
+use crate::utility::{load_acquire_u64, page_size, store_release_u32, store_release_u64, Epoll};
+use libc::{
+    c_void, epoll_event, eventfd, ftruncate, memfd_create, mmap, munmap, write, EFD_CLOEXEC,
+    EFD_NONBLOCK, MAP_ANONYMOUS, MAP_FAILED, MAP_FIXED, MAP_PRIVATE, MAP_SHARED, MFD_CLOEXEC,
+    PROT_NONE, PROT_READ, PROT_WRITE,
+};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::ptr::null_mut;
+use std::sync::Arc;
+use tokio::io::unix::AsyncFd;
+
+/// A BPF ring buffer in userspace memory, laid out like the kernel does: consumer page, producer
+/// page and a data area that is mapped twice, so records can wrap around. It plays the kernel
+/// side and wakes up readers through an eventfd.
+pub(crate) struct SimRing {
+    memfd: OwnedFd,
+    notify: OwnedFd,
+    base: *mut c_void,
+    page_size: usize,
+    size: usize,
+}
+
+/// A record reserved by `SimRing::reserve`, which is busy until it's committed or discarded
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SimRecord {
+    pos: u64,
+    len: usize,
+}
+
+impl SimRing {
+    /// Returns an empty ring with a data area of `size` bytes, a power of two multiple of pages
+    pub(crate) fn new(size: usize) -> Self {
+        let page_size = page_size().unwrap();
+        assert!(size.is_power_of_two() && size >= page_size);
+
+        let memfd = unsafe { memfd_create(c"ringbuf".as_ptr(), MFD_CLOEXEC) };
+        assert!(memfd >= 0);
+        let memfd = unsafe { OwnedFd::from_raw_fd(memfd) };
+
+        let notify = unsafe { eventfd(0, EFD_CLOEXEC | EFD_NONBLOCK) };
+        assert!(notify >= 0);
+        let notify = unsafe { OwnedFd::from_raw_fd(notify) };
+
+        assert_eq!(
+            unsafe { ftruncate(memfd.as_raw_fd(), (2 * page_size + size) as _) },
+            0
+        );
+
+        // Reserve the address space first, so the second mapping of the data area can be
+        // placed right after the first one
+        let base = unsafe {
+            mmap(
+                null_mut(),
+                2 * page_size + 2 * size,
+                PROT_NONE,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(base, MAP_FAILED);
+
+        let pages = unsafe {
+            mmap(
+                base,
+                2 * page_size + size,
+                PROT_READ | PROT_WRITE,
+                MAP_SHARED | MAP_FIXED,
+                memfd.as_raw_fd(),
+                0,
+            )
+        };
+        assert_eq!(pages, base);
+
+        let wrap = unsafe { base.add(2 * page_size + size) };
+        let data = unsafe {
+            mmap(
+                wrap,
+                size,
+                PROT_READ | PROT_WRITE,
+                MAP_SHARED | MAP_FIXED,
+                memfd.as_raw_fd(),
+                (2 * page_size) as _,
+            )
+        };
+        assert_eq!(data, wrap);
+
+        SimRing {
+            memfd,
+            notify,
+            base,
+            page_size,
+            size,
+        }
+    }
+
+    /// Returns a ring buffer reader that consumes from this ring
+    pub(crate) fn ringbuf(&self) -> Ringbuf<'_> {
+        Ringbuf {
+            fd: AsyncFd::new(RingFd::Borrowed(self.notify.as_fd())).unwrap(),
+            ring: Arc::new(self.ring_map()),
+        }
+    }
+
+    /// Returns a blocking ring buffer reader that consumes from this ring
+    pub(crate) fn blocking_ringbuf(&self) -> BlockingRingbuf<'_> {
+        let epoll = Epoll::new().unwrap();
+        epoll.add(&self.notify, 0).unwrap();
+
+        BlockingRingbuf {
+            fd: RingFd::Borrowed(self.notify.as_fd()),
+            epoll,
+            events: [epoll_event { events: 0, u64: 0 }],
+            ring: self.ring_map(),
+        }
+    }
+
+    /// Adds this ring to a set and returns its id
+    pub(crate) fn add_to<'s>(&'s self, set: &mut RingbufSet<'s>) -> usize {
+        set.push(RingFd::Borrowed(self.notify.as_fd()), self.ring_map())
+            .unwrap()
+    }
+
+    /// Maps the ring again the way a user ring buffer is mapped by its producer: a read-only
+    /// consumer page, and the producer page followed by the data area mapped twice. Returns the
+    /// file descriptor to wake up the kernel side with, and the mapping, which is unmapped once
+    /// it's dropped.
+    pub(crate) fn user_ring_map(&self) -> (BorrowedFd<'_>, RingMap) {
+        let consumer = unsafe {
+            mmap(
+                null_mut(),
+                self.page_size,
+                PROT_READ,
+                MAP_SHARED,
+                self.memfd.as_raw_fd(),
+                0,
+            )
+        };
+        assert_ne!(consumer, MAP_FAILED);
+
+        let producer = unsafe {
+            mmap(
+                null_mut(),
+                self.page_size + 2 * self.size,
+                PROT_NONE,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(producer, MAP_FAILED);
+
+        for (at, offset) in [
+            (0, self.page_size),
+            (self.page_size + self.size, 2 * self.page_size),
+        ] {
+            let len = match at {
+                0 => self.page_size + self.size,
+                _ => self.size,
+            };
+
+            let mapped = unsafe {
+                mmap(
+                    producer.add(at),
+                    len,
+                    PROT_READ | PROT_WRITE,
+                    MAP_SHARED | MAP_FIXED,
+                    self.memfd.as_raw_fd(),
+                    offset as _,
+                )
+            };
+            assert_eq!(mapped, unsafe { producer.add(at) });
+        }
+
+        let ring = RingMap {
+            mask: self.size - 1,
+            page_size: self.page_size,
+            consumer,
+            producer,
+            data: unsafe { producer.add(self.page_size) },
+            owned: true,
+        };
+
+        (self.notify.as_fd(), ring)
+    }
+
+    /// Hands over committed records to `f` and skips discarded ones, like
+    /// `bpf_user_ringbuf_drain`. Stops at the first busy record.
+    pub(crate) fn drain<F>(&self, f: F) -> usize
+    where
+        F: FnMut(&[u8]),
+    {
+        self.ring_map().consume(usize::MAX, f)
+    }
+
+    /// Moves both positions to `pos`, e.g. to place the next record across the end of the
+    /// data area. The ring must be empty.
+    pub(crate) fn seek(&self, pos: u64) {
+        assert_eq!(self.consumer_pos(), self.producer_pos());
+
+        store_release_u64(self.base as *mut u64, pos);
+        store_release_u64(self.producer_ptr(), pos);
+    }
+
+    pub(crate) fn consumer_pos(&self) -> u64 {
+        load_acquire_u64(self.base as *const u64)
+    }
+
+    pub(crate) fn producer_pos(&self) -> u64 {
+        load_acquire_u64(self.producer_ptr())
+    }
+
+    /// Reserves a busy record like `bpf_ringbuf_reserve`. Returns `None` if the ring is full.
+    pub(crate) fn reserve(&self, len: usize) -> Option<SimRecord> {
+        let total = roundup_len(len as u32) as u64;
+        let pos = self.producer_pos();
+
+        if pos - self.consumer_pos() + total > self.size as u64 {
+            return None;
+        }
+
+        store_release_u32(self.header(pos), len as u32 | BPF_RINGBUF_BUSY_BIT);
+        store_release_u64(self.producer_ptr(), pos + total);
+
+        Some(SimRecord { pos, len })
+    }
+
+    /// Copies `payload` into a reserved record
+    pub(crate) fn fill(&self, record: &SimRecord, payload: &[u8]) {
+        assert_eq!(record.len, payload.len());
+
+        // SAFETY: The data area is mapped twice, so the record is contiguous even if it wraps
+        unsafe {
+            (self.header(record.pos) as *mut u8)
+                .add(BPF_RINGBUF_HDR_SZ as usize)
+                .copy_from_nonoverlapping(payload.as_ptr(), payload.len());
+        }
+    }
+
+    /// Clears the busy bit of a record like `bpf_ringbuf_submit`
+    pub(crate) fn commit(&self, record: SimRecord) {
+        store_release_u32(self.header(record.pos), record.len as u32);
+        self.notify();
+    }
+
+    /// Marks a record as discarded like `bpf_ringbuf_discard`
+    pub(crate) fn discard(&self, record: SimRecord) {
+        store_release_u32(
+            self.header(record.pos),
+            record.len as u32 | BPF_RINGBUF_DISCARD_BIT,
+        );
+        self.notify();
+    }
+
+    /// Reserves, fills and commits a record like `bpf_ringbuf_output`. Returns false if the ring
+    /// is full.
+    pub(crate) fn produce(&self, payload: &[u8]) -> bool {
+        match self.reserve(payload.len()) {
+            Some(record) => {
+                self.fill(&record, payload);
+                self.commit(record);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn ring_map(&self) -> RingMap {
+        RingMap {
+            mask: self.size - 1,
+            page_size: self.page_size,
+            consumer: self.base,
+            producer: unsafe { self.base.add(self.page_size) },
+            data: self.data(),
+            owned: false,
+        }
+    }
+
+    fn data(&self) -> *mut c_void {
+        unsafe { self.base.add(2 * self.page_size) }
+    }
+
+    fn producer_ptr(&self) -> *mut u64 {
+        unsafe { self.base.add(self.page_size) as *mut u64 }
+    }
+
+    fn header(&self, pos: u64) -> *mut u32 {
+        unsafe { self.data().add(pos as usize & (self.size - 1)) as *mut u32 }
+    }
+
+    fn notify(&self) {
+        let value = 1u64.to_ne_bytes();
+        unsafe { write(self.notify.as_raw_fd(), value.as_ptr() as _, value.len()) };
+    }
+}
+
+impl Drop for SimRing {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.base, 2 * self.page_size + 2 * self.size);
+        }
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// Tracks the heaviest keys of a stream in bounded memory, using the Space-Saving algorithm.
+/// Every key with a weight above `total / capacity` is guaranteed to be tracked. Counts of
+/// tracked keys overestimate the true weight by at most their error.
+#[derive(Debug, Clone)]
+pub struct SpaceSaving<K> {
+    capacity: usize,
+    total: u64,
+    /// Min-heap on the count, so the key to evict is always at the root
+    slots: Vec<Slot<K>>,
+    index: HashMap<K, usize>,
+}
+
+/// A tracked key with its estimated weight
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Estimate<'a, K> {
+    pub key: &'a K,
+    /// Upper bound of the weight of the key
+    pub count: u64,
+    /// Maximum overestimation, so `count - error` is a lower bound
+    pub error: u64,
+}
+
+#[derive(Debug, Clone)]
+struct Slot<K> {
+    key: K,
+    count: u64,
+    error: u64,
+}
+
+impl<K> SpaceSaving<K>
+where
+    K: Hash + Eq + Clone,
+{
+    /// Tracks at most `capacity` keys
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "capacity must not be zero");
+
+        SpaceSaving {
+            capacity,
+            total: 0,
+            slots: Vec::with_capacity(capacity),
+            index: HashMap::with_capacity(capacity),
+        }
+    }
+
+    /// Adds `weight` to `key`. If the key wasn't tracked and all slots are taken, the key with the
+    /// lowest count is replaced and returned.
+    pub fn insert(&mut self, key: K, weight: u64) -> Option<K> {
+        self.total += weight;
+
+        if let Some(&at) = self.index.get(&key) {
+            self.slots[at].count += weight;
+            self.sift_down(at);
+
+            return None;
+        }
+
+        if self.slots.len() < self.capacity {
+            self.index.insert(key.clone(), self.slots.len());
+            self.slots.push(Slot {
+                key,
+                count: weight,
+                error: 0,
+            });
+            self.sift_up(self.slots.len() - 1);
+
+            return None;
+        }
+
+        // The new key inherits the count of the evicted one as its error, as it might have been
+        // seen before without being tracked
+        let root = &mut self.slots[0];
+        let evicted = std::mem::replace(&mut root.key, key.clone());
+        root.error = root.count;
+        root.count += weight;
+
+        self.index.remove(&evicted);
+        self.index.insert(key, 0);
+        self.sift_down(0);
+
+        Some(evicted)
+    }
+
+    /// Returns the estimate of a tracked key
+    pub fn get(&self, key: &K) -> Option<Estimate<'_, K>> {
+        self.index.get(key).map(|&at| self.slots[at].estimate())
+    }
+
+    /// Returns up to `n` tracked keys, heaviest first
+    pub fn top(&self, n: usize) -> Vec<Estimate<'_, K>> {
+        let mut top: Vec<_> = self.slots.iter().map(Slot::estimate).collect();
+
+        top.sort_unstable_by_key(|estimate| std::cmp::Reverse(estimate.count));
+        top.truncate(n);
+
+        top
+    }
+
+    /// Returns an upper bound of the weight of any key that is not tracked
+    pub fn min(&self) -> u64 {
+        match self.slots.len() < self.capacity {
+            true => 0,
+            false => self.slots[0].count,
+        }
+    }
+
+    /// Returns the sum of all weights inserted so far
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn sift_up(&mut self, mut at: usize) {
+        while at > 0 {
+            let parent = (at - 1) / 2;
+
+            if self.slots[parent].count <= self.slots[at].count {
+                break;
+            }
+
+            self.swap(at, parent);
+            at = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut at: usize) {
+        loop {
+            let mut smallest = at;
+
+            for child in [2 * at + 1, 2 * at + 2] {
+                if child < self.slots.len() && self.slots[child].count < self.slots[smallest].count
+                {
+                    smallest = child;
+                }
+            }
+
+            if smallest == at {
+                break;
+            }
+
+            self.swap(at, smallest);
+            at = smallest;
+        }
+    }
+
+    fn swap(&mut self, a: usize, b: usize) {
+        self.slots.swap(a, b);
+        self.index.insert(self.slots[a].key.clone(), a);
+        self.index.insert(self.slots[b].key.clone(), b);
+    }
+}
+
+impl<K> Slot<K> {
+    fn estimate(&self) -> Estimate<'_, K> {
+        Estimate {
+            key: &self.key,
+            count: self.count,
+            error: self.error,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::topk::SpaceSaving;
+
+    #[test]
+    fn exact_below_capacity() {
+        let mut top = SpaceSaving::new(4);
+
+        for key in [1, 2, 2, 3, 3, 3] {
+            assert_eq!(top.insert(key, 1), None);
+        }
+
+        let top = top.top(2);
+        assert_eq!((*top[0].key, top[0].count, top[0].error), (3, 3, 0));
+        assert_eq!((*top[1].key, top[1].count, top[1].error), (2, 2, 0));
+    }
+
+    #[test]
+    fn evicts_lightest() {
+        let mut top = SpaceSaving::new(2);
+
+        top.insert("a", 5);
+        top.insert("b", 1);
+
+        assert_eq!(top.insert("c", 1), Some("b"));
+        assert_eq!(top.min(), 2);
+
+        let c = top.get(&"c").unwrap();
+        assert_eq!((c.count, c.error), (2, 1));
+        assert!(top.get(&"b").is_none());
+    }
+
+    #[test]
+    fn heavy_hitters_survive_flood() {
+        let mut top = SpaceSaving::new(16);
+
+        // Every tenth packet comes from one of two heavy sources, the rest are spoofed
+        for i in 0..100_000u64 {
+            let key = match i % 10 {
+                0 => 0,
+                5 => 1,
+                _ => 1000 + i,
+            };
+
+            top.insert(key, 1);
+        }
+
+        assert_eq!(top.len(), 16);
+        assert_eq!(top.total(), 100_000);
+
+        for key in [0, 1] {
+            let estimate = top.get(&key).unwrap();
+
+            assert!(estimate.count >= 10_000);
+            assert!(estimate.count - estimate.error <= 10_000);
+            assert!(estimate.error <= top.total() / top.capacity() as u64);
+        }
+    }
+}
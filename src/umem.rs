@@ -82,10 +82,9 @@ pub trait UmemStorage {
     fn start(&self) -> NonNull<u8>;
 
     fn length(&self) -> Result<usize> {
-        Ok(self
-            .chunk_size()
+        self.chunk_size()
             .checked_mul(self.num_chunks())
-            .ok_or(Error::Overflow)?)
+            .ok_or(Error::Overflow)
     }
 }
 
@@ -149,13 +148,13 @@ where
         let page_size = page_size()?;
 
         if area.start().as_ptr().align_offset(page_size) != 0 {
-            return Err(Error::UnalignedUmem)?;
+            return Err(Error::UnalignedUmem);
         }
 
         let fd = match fd {
             None => {
                 let socket: RawFd = unsafe_no_panic!(socket(AF_XDP, SOCK_RAW, 0))
-                    .expect(ExpectNotMax, Error::SocketFdInvalid)?;
+                    .expect(ExpectNotMax, |os| Error::SocketFdInvalid { os })?;
 
                 // SAFETY: File Descriptor was properly checked
                 unsafe { OwnedFd::from_raw_fd(socket) }
@@ -172,7 +171,14 @@ where
             &reg as *const _ as _,
             size_of::<UmemReg>() as _
         ))
-        .expect(ExpectDefault, Error::UmemReg)?;
+        .expect(ExpectDefault, |os| Error::UmemReg {
+            address: reg.address,
+            length: reg.length,
+            chunk_size: reg.chunk_size,
+            headroom: reg.headroom,
+            flags: reg.flags,
+            os,
+        })?;
 
         Ok(Umem { area, fd, config })
     }
@@ -192,22 +198,24 @@ impl<const C: usize, const N: usize> ArrayUmem<C, N> {
         }
 
         let page_size = page_size()?;
-        let layout = Layout::from_size_align(C * N, page_size)?;
+        let error = || Error::Allocate {
+            size: C * N,
+            align: page_size,
+        };
+        let layout = Layout::from_size_align(C * N, page_size).map_err(|_| error())?;
 
-        Self::from_raw(
-            unsafe_no_panic!(alloc(layout)).expect(ExpectNonNullPtr, Error::Allocate)? as _,
-        )
+        Self::from_raw(unsafe_no_panic!(alloc(layout)).expect(ExpectNonNullPtr, |_| error())? as _)
     }
 
     fn from_raw(ptr: *mut [[u8; C]; N]) -> Result<Self> {
         let page_size = page_size()?;
 
         if ptr.is_null() {
-            return Err(Error::InvalidUmem)?;
+            return Err(Error::InvalidUmem);
         }
 
         if ptr.align_offset(page_size) != 0 {
-            return Err(Error::UnalignedUmem)?;
+            return Err(Error::UnalignedUmem);
         }
 
         Ok(ArrayUmem {
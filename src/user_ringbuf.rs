@@ -0,0 +1,247 @@
+use crate::ringbuf::{
+    roundup_len, RingMap, BPF_RINGBUF_BUSY_BIT, BPF_RINGBUF_DISCARD_BIT, BPF_RINGBUF_HDR_SZ,
+};
+use crate::utility::{load_acquire_u64, store_release_u32, store_release_u64};
+use crate::{Error, Result};
+use libbpf_rs::{Map, MapType};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::os::fd::{AsFd, BorrowedFd};
+use std::slice;
+
+/// Producer side of a BPF user ring buffer. Samples written here are drained by BPF programs
+/// through `bpf_user_ringbuf_drain`.
+#[derive(Debug)]
+pub struct UserRingbuf<'a> {
+    fd: BorrowedFd<'a>,
+    ring: RingMap,
+}
+
+/// A sample reserved in a user ring buffer. The sample is discarded if it is dropped without
+/// being submitted.
+#[derive(Debug)]
+pub struct UserRingbufSample<'r, 'a> {
+    ringbuf: PhantomData<&'r mut UserRingbuf<'a>>,
+    header: *mut u32,
+    size: usize,
+    done: bool,
+}
+
+impl<'a> UserRingbuf<'a> {
+    /// Returns a BPF user ring buffer from a given Map
+    pub fn from_map(map: &'a Map) -> Result<Self> {
+        Ok(UserRingbuf {
+            fd: map.as_fd(),
+            ring: RingMap::from_fd(map.as_fd(), MapType::UserRingBuf)?,
+        })
+    }
+
+    /// Returns the file descriptor associated with the ring buffer
+    pub fn fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+
+    /// Reserves a sample of `size` bytes. Returns an error if the sample can never fit into the
+    /// ring buffer, or if there is currently not enough space left.
+    pub fn reserve(&mut self, size: usize) -> Result<UserRingbufSample<'_, 'a>> {
+        let capacity = self.ring.mask + 1;
+
+        // The header length must not collide with the busy and discard bits
+        if size >= BPF_RINGBUF_DISCARD_BIT as usize {
+            return Err(Error::SampleSize { size, capacity });
+        }
+
+        let total_size = roundup_len(size as u32) as usize;
+
+        if total_size > capacity {
+            return Err(Error::SampleSize { size, capacity });
+        }
+
+        let consumer_pos = load_acquire_u64(self.ring.consumer as *const u64);
+
+        // We are the only producer, so nobody else writes the producer position
+        let producer_pos = load_acquire_u64(self.ring.producer as *const u64);
+
+        let available = capacity - (producer_pos - consumer_pos) as usize;
+
+        if available < total_size {
+            return Err(Error::UserRingbufFull {
+                size: total_size,
+                available,
+            });
+        }
+
+        // Mark the sample as busy, so the kernel stops draining once it reaches this sample. The
+        // header is published together with the producer position.
+        let header =
+            unsafe { self.ring.data.add(producer_pos as usize & self.ring.mask) } as *mut u32;
+        unsafe {
+            header.write(size as u32 | BPF_RINGBUF_BUSY_BIT);
+            header.add(1).write(0);
+        }
+
+        store_release_u64(
+            self.ring.producer as *mut u64,
+            producer_pos + total_size as u64,
+        );
+
+        Ok(UserRingbufSample {
+            ringbuf: PhantomData,
+            header,
+            size,
+            done: false,
+        })
+    }
+
+    /// Reserves a sample, copies `bytes` into it and submits it
+    pub fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        let mut sample = self.reserve(bytes.len())?;
+        sample.copy_from_slice(bytes);
+        sample.submit();
+
+        Ok(())
+    }
+}
+
+impl<'r, 'a> UserRingbufSample<'r, 'a> {
+    /// Hands the sample over to the kernel
+    pub fn submit(mut self) {
+        self.commit(0);
+    }
+
+    /// Releases the sample without handing it over to the kernel
+    pub fn discard(mut self) {
+        self.commit(BPF_RINGBUF_DISCARD_BIT);
+    }
+
+    /// Clears the busy bit of the sample, which allows the kernel to consume it
+    fn commit(&mut self, flags: u32) {
+        store_release_u32(self.header, self.size as u32 | flags);
+        self.done = true;
+    }
+}
+
+impl<'r, 'a> Deref for UserRingbufSample<'r, 'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        let data = unsafe { (self.header as *const u8).add(BPF_RINGBUF_HDR_SZ as usize) };
+
+        // SAFETY: The data area is mapped twice, so the sample is contiguous even if it wraps
+        unsafe { slice::from_raw_parts(data, self.size) }
+    }
+}
+
+impl<'r, 'a> DerefMut for UserRingbufSample<'r, 'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        let data = unsafe { (self.header as *mut u8).add(BPF_RINGBUF_HDR_SZ as usize) };
+
+        // SAFETY: The data area is mapped twice, so the sample is contiguous even if it wraps
+        unsafe { slice::from_raw_parts_mut(data, self.size) }
+    }
+}
+
+impl<'r, 'a> Drop for UserRingbufSample<'r, 'a> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.commit(BPF_RINGBUF_DISCARD_BIT);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ringbuf::sim::SimRing;
+    use crate::ringbuf::BPF_RINGBUF_HDR_SZ;
+    use crate::user_ringbuf::UserRingbuf;
+    use crate::Error;
+
+    const SIZE: usize = 4096;
+
+    fn user_ringbuf(sim: &SimRing) -> UserRingbuf<'_> {
+        let (fd, ring) = sim.user_ring_map();
+
+        UserRingbuf { fd, ring }
+    }
+
+    fn drain(sim: &SimRing) -> Vec<Vec<u8>> {
+        let mut samples = vec![];
+        sim.drain(|sample| samples.push(sample.to_vec()));
+
+        samples
+    }
+
+    #[test]
+    fn reserve_and_submit() {
+        let sim = SimRing::new(SIZE);
+        let mut ringbuf = user_ringbuf(&sim);
+
+        let mut sample = ringbuf.reserve(5).unwrap();
+        sample.copy_from_slice(&[1; 5]);
+
+        // The sample is busy until it's submitted
+        assert_eq!(drain(&sim), Vec::<Vec<u8>>::new());
+        assert_eq!(sim.producer_pos(), BPF_RINGBUF_HDR_SZ as u64 + 8);
+
+        sample.submit();
+        ringbuf.write(&[2; 8]).unwrap();
+
+        assert_eq!(drain(&sim), vec![vec![1; 5], vec![2; 8]]);
+        assert_eq!(sim.consumer_pos(), sim.producer_pos());
+    }
+
+    #[test]
+    fn dropped_sample_is_discarded() {
+        let sim = SimRing::new(SIZE);
+        let mut ringbuf = user_ringbuf(&sim);
+
+        drop(ringbuf.reserve(16).unwrap());
+        ringbuf.reserve(4).unwrap().discard();
+        ringbuf.write(&[3; 4]).unwrap();
+
+        assert_eq!(drain(&sim), vec![vec![3; 4]]);
+        assert_eq!(sim.consumer_pos(), sim.producer_pos());
+    }
+
+    #[test]
+    fn full_ring_rejects_samples() {
+        let sim = SimRing::new(SIZE);
+        let mut ringbuf = user_ringbuf(&sim);
+
+        assert!(matches!(
+            ringbuf.reserve(SIZE),
+            Err(Error::SampleSize { size: SIZE, .. })
+        ));
+
+        let payload = [0u8; SIZE / 2 - BPF_RINGBUF_HDR_SZ as usize];
+        ringbuf.write(&payload).unwrap();
+        ringbuf.write(&payload).unwrap();
+
+        assert!(matches!(
+            ringbuf.reserve(1),
+            Err(Error::UserRingbufFull {
+                size: 16,
+                available: 0
+            })
+        ));
+
+        // Space is released once the kernel consumed the samples
+        assert_eq!(drain(&sim).len(), 2);
+        ringbuf.write(&[4; 1]).unwrap();
+        assert_eq!(drain(&sim), vec![vec![4; 1]]);
+    }
+
+    #[test]
+    fn sample_wraps_around() {
+        let sim = SimRing::new(SIZE);
+        sim.seek(SIZE as u64 - BPF_RINGBUF_HDR_SZ as u64);
+
+        let mut ringbuf = user_ringbuf(&sim);
+
+        let payload: Vec<u8> = (0..64).collect();
+        ringbuf.write(&payload).unwrap();
+
+        assert_eq!(drain(&sim), vec![payload]);
+        assert_eq!(sim.consumer_pos(), SIZE as u64 + 64);
+    }
+}
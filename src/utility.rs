@@ -1,10 +1,27 @@
 #![allow(path_statements)]
 #![allow(clippy::no_effect)]
 
-use crate::assert::{unsafe_no_panic, ExpectNotZero, ExpectPositive};
+use crate::assert::{
+    unsafe_no_panic, ExpectDefault, ExpectNonNegative, ExpectNotZero, ExpectPositive,
+};
 use crate::{Error, Result};
-use libc::{if_nametoindex, sysconf, _SC_PAGE_SIZE};
+use futures::ready;
+use libc::{
+    c_int, epoll_create1, epoll_ctl, epoll_event, epoll_wait, if_nametoindex, sysconf,
+    _SC_PAGE_SIZE, EPOLLIN, EPOLL_CLOEXEC, EPOLL_CTL_ADD,
+};
 use std::ffi::CString;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use tokio::io::unix::AsyncFd;
+
+/// An epoll instance. File descriptors are registered level-triggered, together with an id that
+/// is reported back once they become readable.
+#[derive(Debug)]
+pub(crate) struct Epoll {
+    fd: OwnedFd,
+}
 
 /// Aligns a value to a given bound
 pub(crate) trait AlignUp {
@@ -23,24 +40,177 @@ impl AlignUp for usize {
     }
 }
 
+impl Epoll {
+    pub(crate) fn new() -> Result<Self> {
+        let fd = unsafe_no_panic!(epoll_create1(EPOLL_CLOEXEC))
+            .expect(ExpectNonNegative, |os| Error::EpollCreate { os })?;
+
+        // SAFETY: File Descriptor was properly checked
+        Ok(Epoll {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    /// Registers a file descriptor for readability
+    pub(crate) fn add<F>(&self, fd: &F, id: usize) -> Result<()>
+    where
+        F: AsRawFd,
+    {
+        let event = epoll_event {
+            events: EPOLLIN as _,
+            u64: id as _,
+        };
+
+        unsafe_no_panic!(epoll_ctl(
+            self.fd.as_raw_fd(),
+            EPOLL_CTL_ADD,
+            fd.as_raw_fd(),
+            &event as *const _ as _,
+        ))
+        .expect(ExpectDefault, |os| Error::EpollCtl {
+            fd: fd.as_raw_fd(),
+            os,
+        })?;
+
+        Ok(())
+    }
+
+    /// Waits up to `timeout` milliseconds for registered file descriptors to become readable and
+    /// returns their events. A timeout of zero returns immediately, -1 blocks indefinitely.
+    pub(crate) fn wait<'e>(
+        &self,
+        events: &'e mut [epoll_event],
+        timeout: c_int,
+    ) -> std::io::Result<&'e [epoll_event]> {
+        let ready = unsafe {
+            epoll_wait(
+                self.fd.as_raw_fd(),
+                events.as_mut_ptr(),
+                events.len() as _,
+                timeout,
+            )
+        };
+
+        if ready < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(&events[..ready as usize])
+    }
+}
+
+/// Polls an epoll instance for readiness until at least one record was consumed. `consume` is
+/// called with the id of each readable file descriptor and the number of records that are left
+/// until `limit`, and returns the number of records it consumed.
+pub(crate) fn poll_dispatch<F>(
+    epoll: &AsyncFd<Epoll>,
+    events: &mut [epoll_event],
+    cx: &mut Context<'_>,
+    limit: usize,
+    mut consume: F,
+) -> Poll<std::io::Result<usize>>
+where
+    F: FnMut(u64, usize) -> usize,
+{
+    if limit == 0 {
+        return Poll::Ready(Ok(0));
+    }
+
+    loop {
+        let mut guard = ready!(epoll.poll_read_ready(cx))?;
+
+        // File descriptors are registered level-triggered, so every one with unconsumed records
+        // is reported, including those we left behind because of the limit
+        let ready = epoll.get_ref().wait(events, 0)?;
+        let mut count = 0;
+
+        for event in ready {
+            count += consume(event.u64, limit - count);
+
+            if count == limit {
+                break;
+            }
+        }
+
+        if count > 0 {
+            return Poll::Ready(Ok(count));
+        }
+
+        // Nothing was ready, so we wait for the next notification
+        guard.clear_ready();
+    }
+}
+
+impl AsRawFd for Epoll {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+/// Loads a ring position that is shared with the kernel, like `smp_load_acquire`. The pointer
+/// must be aligned and valid for as long as the ring is mapped.
+#[inline(always)]
+pub(crate) fn load_acquire_u64(ptr: *const u64) -> u64 {
+    unsafe { AtomicU64::from_ptr(ptr as *mut u64) }.load(Ordering::Acquire)
+}
+
+/// Stores a ring position that is shared with the kernel, like `smp_store_release`
+#[inline(always)]
+pub(crate) fn store_release_u64(ptr: *mut u64, val: u64) {
+    unsafe { AtomicU64::from_ptr(ptr) }.store(val, Ordering::Release)
+}
+
+/// Like `load_acquire_u64`, for 32 bit ring positions and record headers
+#[inline(always)]
+pub(crate) fn load_acquire_u32(ptr: *const u32) -> u32 {
+    unsafe { AtomicU32::from_ptr(ptr as *mut u32) }.load(Ordering::Acquire)
+}
+
+/// Like `store_release_u64`, for 32 bit ring positions and record headers
+#[inline(always)]
+pub(crate) fn store_release_u32(ptr: *mut u32, val: u32) {
+    unsafe { AtomicU32::from_ptr(ptr) }.store(val, Ordering::Release)
+}
+
 #[cfg(target_os = "linux")]
 pub fn ifindex<I>(name: I) -> Result<u32>
 where
     I: Into<String>,
 {
-    let name = CString::new(name.into())?;
+    let name = name.into();
+    let cname = CString::new(name.as_str()).map_err(|_| Error::InvalidName(name.clone()))?;
 
-    unsafe_no_panic!(if_nametoindex(name.as_ref() as *const _ as _))
-        .expect(ExpectNotZero, Error::InterfaceInvalid)
+    unsafe_no_panic!(if_nametoindex(cname.as_ref() as *const _ as _))
+        .expect(ExpectNotZero, |os| Error::InterfaceInvalid { name, os })
 }
 
 #[cfg(target_os = "linux")]
 pub(crate) fn page_size() -> Result<usize> {
     unsafe_no_panic!(sysconf(_SC_PAGE_SIZE))
-        .expect(ExpectPositive, Error::PageSizeInvalid)
+        .expect(ExpectPositive, |os| Error::PageSizeInvalid { os })
         .map(|ok| ok as usize)
 }
 
+/// Returns the CPUs that are currently online, as listed in `/sys/devices/system/cpu/online`
+#[cfg(target_os = "linux")]
+pub fn online_cpus() -> Result<Vec<usize>> {
+    let online = std::fs::read_to_string("/sys/devices/system/cpu/online")?;
+    let parse = |cpu: &str| cpu.parse().map_err(|_| Error::CpuList(online.clone()));
+    let mut cpus = vec![];
+
+    // The list is made of comma-separated single CPUs and ranges, e.g. "0-3,5,7-8"
+    for range in online.trim().split(',').filter(|range| !range.is_empty()) {
+        let (start, end): (usize, usize) = match range.split_once('-') {
+            Some((start, end)) => (parse(start)?, parse(end)?),
+            None => (parse(range)?, parse(range)?),
+        };
+
+        cpus.extend(start..=end);
+    }
+
+    Ok(cpus)
+}
+
 #[cfg(not(target_os = "linux"))]
 pub(crate) unsafe fn page_size() -> i64 {
     unimplemented!("Page-aligned ArrayUmem is only supported on Linux")